@@ -0,0 +1,85 @@
+use std::io::{self, SeekFrom};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use crate::{DetectError, DetectionResult, MediaTypeDetector, PrefixBuffer, SeekablePass, SeekableInput};
+
+// The detected type along with the bytes taken from the reader to find it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SniffedType {
+    pub result: Option<DetectionResult>,
+    // Replay these ahead of the rest of the reader to see the whole input
+    pub prefix: Vec<u8>,
+}
+
+impl MediaTypeDetector {
+    // Detect the media type of an async stream, reading only as much as the rules need
+    pub async fn detect_reader_async<R: AsyncRead + Unpin>(&self, reader: R) -> Result<SniffedType, DetectError> {
+        self.detect_async_reader_type(&None, reader).await
+    }
+
+    // Detect the media type of an async stream with a name to match globs against
+    pub async fn detect_named_reader_async<R: AsyncRead + Unpin>(
+        &self,
+        resource_name: &str,
+        reader: R,
+    ) -> Result<SniffedType, DetectError> {
+        self.detect_async_reader_type(&Some(resource_name.into()), reader).await
+    }
+
+    // Detect the media type of a seekable async input, leaving it positioned after the last range read
+    pub async fn detect_seekable_async<R: AsyncRead + AsyncSeek + Unpin>(
+        &self,
+        reader: R,
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_async_seekable_type(&None, reader).await
+    }
+
+    // Detect the media type of a seekable async input with a name to match globs against
+    pub async fn detect_named_seekable_async<R: AsyncRead + AsyncSeek + Unpin>(
+        &self,
+        resource_name: &str,
+        reader: R,
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_async_seekable_type(&Some(resource_name.into()), reader).await
+    }
+
+    async fn detect_async_reader_type<R: AsyncRead + Unpin>(
+        &self,
+        resource_name: &Option<String>,
+        mut reader: R,
+    ) -> Result<SniffedType, DetectError> {
+        let mut prefix: PrefixBuffer = PrefixBuffer::new(self.read_ahead());
+
+        while let Some(buf) = prefix.unfilled() {
+            let read: io::Result<usize> = reader.read(buf).await;
+
+            prefix.record(read)?;
+        }
+
+        let prefix: Vec<u8> = prefix.into_prefix();
+
+        Ok(SniffedType { result: self.detect_prefix_type(resource_name, &prefix)?, prefix })
+    }
+
+    async fn detect_async_seekable_type<R: AsyncRead + AsyncSeek + Unpin>(
+        &self,
+        resource_name: &Option<String>,
+        mut reader: R,
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        let len: u64 = reader.seek(SeekFrom::End(0)).await?;
+
+        let mut input: SeekableInput = self.seekable_input(len);
+
+        loop {
+            for (position, buf) in input.reads() {
+                reader.seek(position).await?;
+                reader.read_exact(buf).await?;
+            }
+
+            if let SeekablePass::Detected(result) = self.detect_seekable_pass(resource_name, &mut input)? {
+                return Ok(result);
+            }
+        }
+    }
+}
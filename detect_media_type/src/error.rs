@@ -0,0 +1,39 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum DetectError {
+    // Opening or reading the input failed
+    Io(io::Error),
+    // Directories and other special files have no content to inspect
+    NotARegularFile(PathBuf),
+    // There were no bytes to inspect
+    EmptyInput,
+}
+
+impl Display for DetectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DetectError::Io(error) => write!(f, "Failed to read input: {}", error),
+            DetectError::NotARegularFile(path) => write!(f, "{} is not a regular file", path.display()),
+            DetectError::EmptyInput => write!(f, "Input is empty"),
+        }
+    }
+}
+
+impl Error for DetectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DetectError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DetectError {
+    fn from(error: io::Error) -> Self {
+        DetectError::Io(error)
+    }
+}
@@ -0,0 +1,86 @@
+use rule_def::{GlobRule, GlobType, MagicRule, MatchValueType};
+
+use crate::DetectionResult;
+
+// Everything the detector looked at while deciding on a type
+#[derive(Clone, Debug)]
+pub struct DetectionTrace {
+    pub magic_rules: Vec<MagicRuleTrace>,
+    pub globs: Vec<GlobTrace>,
+    pub result: Option<DetectionResult>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MagicRuleTrace {
+    pub media_type: String,
+    pub priority: u8,
+    pub outcome: MagicRuleOutcome,
+}
+
+impl MagicRuleTrace {
+    pub(crate) fn new(magic_rule: &MagicRule, outcome: MagicRuleOutcome) -> MagicRuleTrace {
+        MagicRuleTrace { media_type: magic_rule.media_type.to_string(), priority: magic_rule.priority, outcome }
+    }
+}
+
+// Every rule is run from the lowest priority up with no cutoff, so a rule is only skipped for a subtype
+#[derive(Clone, Debug)]
+pub enum MagicRuleOutcome {
+    Matched(Vec<ConditionTrace>),
+    NotMatched(Vec<ConditionTrace>),
+    // The named subtype had already matched so this rule couldn't add anything
+    SkippedBySubType(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum ConditionTrace {
+    Single(ClauseTrace),
+    Multi {
+        min_to_match: u8,
+        matched: bool,
+        clauses: Vec<ClauseTrace>,
+    },
+}
+
+impl ConditionTrace {
+    pub fn matched(&self) -> bool {
+        match self {
+            ConditionTrace::Single(clause) => clause.matched,
+            ConditionTrace::Multi { matched, .. } => *matched,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClauseTrace {
+    // Range of offsets the clause may start at
+    pub from: u32,
+    pub to: u32,
+    // The offsets count back from the end of the input
+    pub from_end: bool,
+    pub value_type: MatchValueType,
+    // Where the value itself was found, regardless of the nested clauses
+    pub matched_at: Option<u64>,
+    // The value was found and so was at least one nested clause, if there are any
+    pub matched: bool,
+    pub children: Vec<ClauseTrace>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GlobTrace {
+    pub media_type: String,
+    pub pattern: String,
+    pub glob_type: GlobType,
+    pub matched: bool,
+}
+
+impl GlobTrace {
+    pub(crate) fn new(glob_rule: &GlobRule, matched: bool) -> GlobTrace {
+        GlobTrace {
+            media_type: glob_rule.media_type.to_string(),
+            pattern: glob_rule.pattern.to_string(),
+            glob_type: glob_rule.glob_type.clone(),
+            matched,
+        }
+    }
+}
@@ -1,6 +1,52 @@
-use rule_def::GlobRule;
+use regex::Regex;
 
-pub(crate) fn run_glob(resource_name: &str, glob_rule: &GlobRule) -> bool {
-    // TODO impl other globtypes inc regex
-    resource_name.ends_with(&glob_rule.pattern)
-}
\ No newline at end of file
+use rule_def::{full_match_regex, GlobRule, GlobType};
+
+const ASTERISK: char = '*';
+
+// Regex globs compiled when a detector is created, indexed like the registry's glob rules
+pub(crate) struct GlobRegexes(Vec<Option<Regex>>);
+
+impl GlobRegexes {
+    pub(crate) fn new(glob_rules: &[GlobRule]) -> GlobRegexes {
+        GlobRegexes(glob_rules.iter()
+            .map(|glob_rule| match glob_rule.glob_type {
+                // None if the pattern can't be compiled, which can never match
+                GlobType::Regex => Regex::new(&full_match_regex(&glob_rule.pattern)).ok(),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> Option<&Regex> {
+        self.0.get(idx).and_then(Option::as_ref)
+    }
+}
+
+// Regex globs need the compiled form of their pattern, other globs pass None
+pub(crate) fn run_glob(resource_name: &str, glob_rule: &GlobRule, regex: Option<&Regex>) -> bool {
+    match glob_rule.glob_type {
+        GlobType::Regex => regex.is_some_and(|regex| regex.is_match(resource_name)),
+        _ => run_literal(resource_name, &glob_rule.pattern, &glob_rule.glob_type),
+    }
+}
+
+// Tika tries a name in its own case before ignoring case, so *.C is only a C++ source for foo.C
+pub(crate) fn matches_declared_case(resource_name: &str, glob_rule: &GlobRule) -> bool {
+    match glob_rule.glob_type {
+        // Insensitive regexes match any case so only sensitive ones are known to match as declared
+        GlobType::Regex => glob_rule.case_sensitive,
+        // Literal globs are declared with their wildcards at either end
+        _ => run_literal(resource_name, glob_rule.declared.trim_matches(ASTERISK), &glob_rule.glob_type),
+    }
+}
+
+fn run_literal(resource_name: &str, pattern: &str, glob_type: &GlobType) -> bool {
+    match glob_type {
+        GlobType::Exact => resource_name == pattern,
+        GlobType::EndsWith => resource_name.ends_with(pattern),
+        GlobType::StartsWith => resource_name.starts_with(pattern),
+        GlobType::Contains => resource_name.contains(pattern),
+        GlobType::Regex => false,
+    }
+}
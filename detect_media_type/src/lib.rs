@@ -2,86 +2,483 @@
 
 extern crate core;
 
+use std::borrow::Cow;
+use std::cmp::{min, Ordering};
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, Metadata};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
 
 use log::debug;
+use regex::Regex;
 
-use rule_def::MediaTypeRegistry;
+use rule_def::{GlobRule, GlobType, MagicRule, MediaTypeMetadata, MediaTypeRegistry, ReadPlan, trim_parameters};
+use rule_gen::{READ_PLAN, TYPE_REGISTRY};
 
-use crate::glob::run_glob;
-use crate::magic::run_magic;
+use crate::glob::{matches_declared_case, run_glob, GlobRegexes};
+use crate::magic::{run_magic, trace_magic, MagicInput, MagicRegexes};
+use crate::pool::MagicPool;
+use crate::root_xml::{extract_root_element, run_root_xml, RootElement};
+
+#[cfg(feature = "tokio")]
+pub use crate::async_io::SniffedType;
+pub use crate::error::DetectError;
+pub use crate::sniffer::{SniffState, Sniffer};
+pub use crate::explain::{ClauseTrace, ConditionTrace, DetectionTrace, GlobTrace, MagicRuleOutcome, MagicRuleTrace};
 
 mod magic;
 mod glob;
+mod root_xml;
+mod explain;
+mod error;
+mod sniffer;
+mod pool;
+#[cfg(feature = "tokio")]
+mod async_io;
+
+const EXTENSION_DOT: char = '.';
+
+const XML_MEDIA_TYPE: &str = "application/xml";
 
-const EXTENSION_DOT: &str = ".";
+const PARAMETER_SEPARATOR: char = ';';
 
 pub struct DetectorConfig {
+    // Match the resource name against glob rules
     pub enable_glob: bool,
+    // Match the content against magic rules
     pub enable_magic: bool,
+    // Prefer the glob match over the magic match when both exist
     pub prioritise_glob: bool,
+    // Threads started with the detector to run magic rules, capped at the available cores
+    // 0 or 1 runs them on the calling thread
     pub max_concurrency: u8,
+    // Returned when nothing matches, empty to return None instead
     pub default_type: &'static str,
+    // Caps the bytes read from an input, 0 reads as much as the rules need
+    pub max_read_ahead: usize,
+}
+
+// Both stages enabled with content trusted over the name, as Tika does
+impl Default for DetectorConfig {
+    fn default() -> DetectorConfig {
+        DetectorConfig {
+            enable_glob: true,
+            enable_magic: true,
+            prioritise_glob: false,
+            max_concurrency: 0,
+            default_type: "",
+            max_read_ahead: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DetectionSource {
+    // The resource name matched a glob
+    Glob,
+    // The content matched a magic rule
+    Magic,
+    // The resource name narrowed down a more general magic match
+    Hint,
+    // Nothing matched so the configured default was used
+    Fallback,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectionResult {
+    pub media_type: String,
+    pub source: DetectionSource,
+    // Priority of the best magic rule, if the content had a say in the result
+    pub priority: Option<u8>,
+    // Every type matched by the rules, best first
+    pub candidates: Vec<String>,
+    // Glob and magic matched unrelated types so the config had to pick one
+    pub disagreed: bool,
 }
 
 pub struct MediaTypeDetector {
-    pub registry: MediaTypeRegistry,
+    // Private as the compiled regexes are tied to the rules they came from
+    // Shared with the magic pool's threads
+    registry: Arc<MediaTypeRegistry>,
 
     pub config: DetectorConfig,
+
+    magic_regexes: Arc<MagicRegexes>,
+    glob_regexes: GlobRegexes,
+    read_plan: ReadPlan,
+    magic_pool: Option<MagicPool>,
 }
 
 impl MediaTypeDetector {
+    // Regex magic is compiled as each rule is first run so creating a detector stays cheap
+    // The few regex globs are compiled up front as most names are checked against them
+    pub fn new(registry: MediaTypeRegistry, config: DetectorConfig) -> MediaTypeDetector {
+        let magic_regexes: MagicRegexes = MagicRegexes::new(&registry.magic_rules);
+        let glob_regexes: GlobRegexes = GlobRegexes::new(&registry.glob_rules);
+
+        // The generated plan only holds while the magic rules are still the compiled ones
+        let read_plan: ReadPlan = match &registry.magic_rules {
+            Cow::Borrowed(magic_rules) if ptr::eq(*magic_rules, TYPE_REGISTRY.magic_rules.as_ref()) => READ_PLAN.clone(),
+            magic_rules => ReadPlan::new(magic_rules),
+        };
+
+        let registry: Arc<MediaTypeRegistry> = Arc::new(registry);
+        let magic_regexes: Arc<MagicRegexes> = Arc::new(magic_regexes);
+
+        // Threads sharing a core only add the cost of handing work between them
+        let cores: usize = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+        let magic_pool: Option<MagicPool> = match min(usize::from(config.max_concurrency), cores) {
+            0 | 1 => None,
+            threads => Some(MagicPool::new(threads, &registry, &magic_regexes)),
+        };
+
+        MediaTypeDetector { registry, config, magic_regexes, glob_regexes, read_plan, magic_pool }
+    }
+
+    // The rules the detector was created with
+    pub fn registry(&self) -> &MediaTypeRegistry {
+        &self.registry
+    }
+
     // Detect the media type of a file on the file system
-    pub fn detect_file_type(&self, path: &Path) -> Option<String> {
+    pub fn detect_file_type(&self, path: &Path) -> Result<Option<DetectionResult>, DetectError> {
         let file_name: Option<String> = path.file_name()
             // Convert to String representation
             .map(|name: &OsStr| name.to_string_lossy().into_owned());
 
-        // Buffer to hold a portion of the file to test magic against
-        // TODO generate a const value in build.rs to be the max size of this vec
-        let mut buf: Vec<u8> = vec![0; 1024];
+        let file: File = File::open(path)?;
+
+        let metadata: Metadata = file.metadata()?;
+
+        if !metadata.is_file() {
+            return Err(DetectError::NotARegularFile(path.to_path_buf()));
+        }
+
+        // Files such as those in /proc report no length so can only be streamed
+        if metadata.len() == 0 {
+            return self.detect_reader_type(&file_name, file);
+        }
 
-        File::open(&path).map(|mut file: File| {
-            file.read(buf.as_mut_slice()).expect("Failed to read bytes from file");
+        self.detect_seekable_type(&file_name, file)
+    }
+
+    // Detect the media type of content already held in memory
+    pub fn detect_bytes(&self, buf: &[u8]) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_buffer_type(&None, MagicInput::new(buf, true))
+    }
 
-            self.detect_type(&file_name, buf.as_slice())
-        }).unwrap()
+    // Detect the media type of a stream, reading only as much as the rules need
+    pub fn detect_reader<R: Read>(&self, reader: R) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_reader_type(&None, reader)
+    }
+
+    // Detect the media type of a stream with a name to match globs against, e.g. an upload's file name
+    pub fn detect_named_reader<R: Read>(&self, resource_name: &str, reader: R) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_reader_type(&Some(resource_name.into()), reader)
+    }
+
+    // Detect the media type of a seekable input, reading only the ranges the rules compare
+    pub fn detect_seekable<R: Read + Seek>(&self, reader: R) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_seekable_type(&None, reader)
+    }
+
+    // Detect the media type of a seekable input with a name to match globs against
+    pub fn detect_named_seekable<R: Read + Seek>(&self, resource_name: &str, reader: R) -> Result<Option<DetectionResult>, DetectError> {
+        self.detect_seekable_type(&Some(resource_name.into()), reader)
+    }
+
+    // Convenience for callers happy to crash when the file can't be read
+    pub fn detect_file_type_or_panic(&self, path: &Path) -> Option<DetectionResult> {
+        self.detect_file_type(path)
+            .unwrap_or_else(|error| panic!("Failed to detect type of {}: {}", path.display(), error))
+    }
+
+    // Threads running magic rules alongside the caller's, 0 when they run on the calling thread
+    pub fn magic_threads(&self) -> usize {
+        self.magic_pool.as_ref().map_or(0, MagicPool::threads)
+    }
+
+    // Bytes to read from the start of an input before running the rules
+    pub fn read_ahead(&self) -> usize {
+        match self.config.max_read_ahead {
+            0 => self.read_plan.read_ahead,
+            max_read_ahead => min(max_read_ahead, self.read_plan.read_ahead)
+        }
+    }
+
+    // Detect the media type of a resource from its name alone
+    pub fn detect_name_type(&self, resource_name: &str) -> Option<String> {
+        self.run_glob_rules(resource_name)
+    }
+
+    // Trace the rules evaluated for a resource, to see why it was given a type
+    pub fn explain_type(&self, resource_name: Option<&str>, buf: &[u8]) -> DetectionTrace {
+        let resource_name: Option<String> = resource_name.map(String::from);
+
+        let mut magic_rules: Vec<MagicRuleTrace> = vec![];
+
+        if self.config.enable_magic {
+            self.run_magic_rules(&MagicInput::new(buf, true), Some(&mut magic_rules));
+        }
+
+        let globs: Vec<GlobTrace> = match &resource_name {
+            Some(resource_name) if self.config.enable_glob => self.trace_glob_rules(resource_name),
+            _ => vec![]
+        };
+
+        DetectionTrace { magic_rules, globs, result: self.detect_type(&resource_name, &MagicInput::new(buf, true)) }
     }
 
     // TODO recursive child types
 
-    fn detect_type(
+    fn detect_reader_type<R: Read>(
+        &self,
+        resource_name: &Option<String>,
+        mut reader: R,
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        let mut prefix: PrefixBuffer = PrefixBuffer::new(self.read_ahead());
+
+        while let Some(buf) = prefix.unfilled() {
+            let read: io::Result<usize> = reader.read(buf);
+
+            prefix.record(read)?;
+        }
+
+        self.detect_prefix_type(resource_name, &prefix.into_prefix())
+    }
+
+    fn detect_seekable_type<R: Read + Seek>(
         &self,
         resource_name: &Option<String>,
-        buf: &[u8],
-    ) -> Option<String> {
-        // Run all rules and return possible matches
-        let matches: Vec<String> = self.run_magic_rules(buf);
+        mut reader: R,
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        let len: u64 = reader.seek(SeekFrom::End(0))?;
+
+        let mut input: SeekableInput = self.seekable_input(len);
 
-        // Try to narrow down matches via glob rules
-        if let Some(resource_name) = resource_name {
-            let glob_match: Option<String> = self.run_glob_rules(resource_name);
+        loop {
+            for (position, buf) in input.reads() {
+                reader.seek(position)?;
+                reader.read_exact(buf)?;
+            }
 
-            if glob_match.is_some() {
-                return glob_match;
+            if let SeekablePass::Detected(result) = self.detect_seekable_pass(resource_name, &mut input)? {
+                return Ok(result);
             }
         }
+    }
+
+    // Detect from the start of a stream, read until full or the stream ended
+    pub(crate) fn detect_prefix_type(
+        &self,
+        resource_name: &Option<String>,
+        prefix: &[u8],
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        // Stopping short of the read ahead means the whole input was read
+        self.detect_buffer_type(resource_name, MagicInput::new(prefix, prefix.len() < self.read_ahead()))
+    }
+
+    // Detect from the ranges read so far, asking for the gaps between them when the result depends on them
+    pub(crate) fn detect_seekable_pass(
+        &self,
+        resource_name: &Option<String>,
+        input: &mut SeekableInput,
+    ) -> Result<SeekablePass, DetectError> {
+        let result: Option<DetectionResult> = self.detect_buffer_type(resource_name, input.magic_input())?;
+
+        if self.needs_gaps(input, &result) {
+            input.read_gaps();
+
+            return Ok(SeekablePass::NeedsGaps);
+        }
+
+        Ok(SeekablePass::Detected(result))
+    }
+
+    // The root element is found by scanning the head as text, so XML is detected again once the gaps are read
+    fn needs_gaps(&self, input: &SeekableInput, result: &Option<DetectionResult>) -> bool {
+        input.has_gaps() && !self.registry.root_xml_rules.is_empty() && result.as_ref()
+            .is_some_and(|result| result.candidates.iter().any(|candidate| self.is_xml(candidate)))
+    }
+
+    // Buffers for the parts of a seekable input of this length which the rules compare
+    pub(crate) fn seekable_input(&self, len: u64) -> SeekableInput {
+        let head_len: usize = min(len, self.read_ahead() as u64) as usize;
+
+        let windows: Vec<Range<usize>> = self.read_plan.head_windows.iter()
+            .take_while(|window| window.start < head_len)
+            .map(|window| window.start..min(window.end, head_len))
+            .collect();
+
+        SeekableInput {
+            head: vec![0; head_len],
+            tail: vec![0; min(len, self.read_behind() as u64) as usize],
+            unread: windows.clone(),
+            windows,
+            tail_unread: true,
+            len,
+        }
+    }
+
+    pub(crate) fn detect_buffer_type(
+        &self,
+        resource_name: &Option<String>,
+        input: MagicInput,
+    ) -> Result<Option<DetectionResult>, DetectError> {
+        // Checked after reading as special files can report a zero length
+        if input.head.is_empty() {
+            return Err(DetectError::EmptyInput);
+        }
+
+        Ok(self.detect_type(resource_name, &input))
+    }
+
+    // Bytes to read from the end of a seekable input
+    pub(crate) fn read_behind(&self) -> usize {
+        self.read_plan.read_behind
+    }
+
+    pub(crate) fn detect_type(
+        &self,
+        resource_name: &Option<String>,
+        input: &MagicInput,
+    ) -> Option<DetectionResult> {
+        let magic_matches: Vec<&MagicRule> = if self.config.enable_magic {
+            self.run_magic_rules(input, None)
+        } else {
+            vec![]
+        };
 
         // The last result will be the best match
-        matches.last().map(|media_type| media_type.clone())
+        let best_magic_rule: Option<&MagicRule> = magic_matches.last().copied();
+
+        let magic_match: Option<String> = best_magic_rule.map(|magic_rule| {
+            // Narrow down generic XML to a specific dialect
+            self.run_root_xml_rules(&magic_rule.media_type, input.head).unwrap_or(magic_rule.media_type.to_string())
+        });
+
+        let glob_match: Option<String> = match resource_name {
+            Some(resource_name) if self.config.enable_glob => self.run_glob_rules(resource_name),
+            _ => None
+        };
+
+        // As in Tika the name may only specialise matches as good as the best, not those it outranked
+        let best_magic_matches: Vec<&MagicRule> = magic_matches.iter()
+            .filter(|magic_rule| best_magic_rule.is_some_and(|best| best.priority == magic_rule.priority))
+            .copied()
+            .collect();
+
+        let (source, disagreed): (DetectionSource, bool) = match (&glob_match, &magic_match) {
+            (Some(glob_match), Some(magic_match)) => self.reconcile(glob_match, magic_match, &best_magic_matches),
+            (Some(_), None) => (DetectionSource::Glob, false),
+            (None, Some(_)) => (DetectionSource::Magic, false),
+            (None, None) => return self.default_type()
+        };
+
+        let media_type: String = match source {
+            DetectionSource::Magic => magic_match.clone(),
+            _ => glob_match.clone()
+        }?;
+
+        // Rank the chosen type first, then the remaining content and name matches
+        let mut candidates: Vec<String> = vec![];
+
+        let ranked = [media_type.clone()].into_iter()
+            .chain(magic_match)
+            .chain(magic_matches.iter().rev().map(|magic_rule| magic_rule.media_type.to_string()))
+            .chain(glob_match);
+
+        for candidate in ranked {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+
+        Some(DetectionResult {
+            media_type,
+            source,
+            priority: best_magic_rule
+                .filter(|_| source != DetectionSource::Glob)
+                .map(|magic_rule| magic_rule.priority),
+            candidates,
+            disagreed,
+        })
+    }
+
+    // Like Tika the name is trusted when it specialises any magic match, e.g. a docx is a zip
+    fn reconcile(&self, glob_match: &String, magic_match: &String, magic_matches: &[&MagicRule]) -> (DetectionSource, bool) {
+        let specialises = |media_type: &str| self.is_canonical_sub_type(media_type, glob_match);
+
+        if glob_match == magic_match {
+            (DetectionSource::Magic, false)
+        } else if specialises(magic_match) || magic_matches.iter().any(|magic_rule| specialises(&magic_rule.media_type)) {
+            (DetectionSource::Hint, false)
+        } else if self.is_canonical_sub_type(glob_match, magic_match) {
+            // The content is more specific than the name
+            (DetectionSource::Magic, false)
+        } else if self.config.prioritise_glob {
+            (DetectionSource::Glob, true)
+        } else {
+            (DetectionSource::Magic, true)
+        }
+    }
+
+    fn default_type(&self) -> Option<DetectionResult> {
+        Some(self.config.default_type)
+            .filter(|default_type| !default_type.is_empty())
+            .map(|default_type| DetectionResult {
+                media_type: default_type.into(),
+                source: DetectionSource::Fallback,
+                priority: None,
+                candidates: vec![],
+                disagreed: false,
+            })
     }
 
-    fn is_sub_type(&self, parent: &String, potential_child: &String) -> bool {
+    // Resolve an alias such as text/javascript to the canonical name returned by detection
+    pub fn canonical_type(&self, media_type: &str) -> String {
+        let normalised: String = normalise_media_type(media_type);
+
+        // Aliases are keyed in lower case, parameters included
+        if let Some(canonical) = self.registry.aliases.get(&normalised.to_ascii_lowercase()) {
+            return canonical.to_string();
+        }
+
+        // Keep any parameters on the canonical form of the base type
+        match normalised.split_once(PARAMETER_SEPARATOR) {
+            Some((base, parameters)) => match self.registry.aliases.get(base) {
+                Some(canonical) => format!("{}{}{}", canonical, PARAMETER_SEPARATOR, parameters),
+                None => normalised
+            },
+            None => normalised
+        }
+    }
+
+    // Look up the description, acronym, links and UTI of a type or its alias
+    pub fn metadata(&self, media_type: &str) -> Option<&MediaTypeMetadata> {
+        self.registry.metadata.get(&self.canonical_type(media_type))
+    }
+
+    // Check if a type descends from a parent, either of which may be an alias
+    pub fn is_sub_type(&self, parent: &str, potential_child: &str) -> bool {
+        self.is_canonical_sub_type(&self.canonical_type(parent), &self.canonical_type(potential_child))
+    }
+
+    // Registry types are already canonical so can skip alias resolution
+    fn is_canonical_sub_type(&self, parent: &str, potential_child: &str) -> bool {
         match self.registry.sub_types.get(parent) {
             Some(types) => {
-                if types.contains(potential_child) {
+                if types.iter().any(|child| child == potential_child) {
                     true
                 } else {
                     // Recurse through the children of this parent
-                    types.iter().any(|child| self.is_sub_type(child, potential_child))
+                    types.iter().any(|child| self.is_canonical_sub_type(child, potential_child))
                 }
             }
             None => false
@@ -90,58 +487,288 @@ impl MediaTypeDetector {
 
     fn run_magic_rules(
         &self,
-        buf: &[u8],
-    ) -> Vec<String> {
-        let mut possible_types: Vec<String> = vec![];
-
-        // The priority of the last match
-        let mut last_match_priority: u8 = 0;
+        input: &MagicInput,
+        mut trace: Option<&mut Vec<MagicRuleTrace>>,
+    ) -> Vec<&MagicRule> {
+        let mut possible_types: Vec<&MagicRule> = vec![];
 
-        for magic_rule in &self.registry.magic_rules {
-            let media_type: &String = &magic_rule.media_type;
+        // Tracing evaluates every clause itself so gains nothing from threads
+        let concurrent_matches: Option<Vec<bool>> = match trace {
+            Some(_) => None,
+            None => self.run_magic_concurrently(input)
+        };
 
-            if magic_rule.priority < last_match_priority {
-                // None of the following clauses will have higher priority
-                return possible_types;
-            }
+        for (idx, magic_rule) in self.registry.magic_rules.iter().enumerate() {
+            let media_type: &str = &magic_rule.media_type;
 
             // Skip rule if we've already matched a child type
-            if possible_types.iter().any(|possible|
-                self.is_sub_type(media_type, possible)) {
+            if let Some(child) = possible_types.iter().find(|possible|
+                self.is_canonical_sub_type(media_type, &possible.media_type)) {
                 debug!("Skipping check for {} as there's already a more specific match", media_type);
 
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push(MagicRuleTrace::new(magic_rule, MagicRuleOutcome::SkippedBySubType(child.media_type.to_string())));
+                }
+
                 continue;
             }
 
-            // TODO order rules in order of priority - highest to lowest
-            // Then stop iteration once we hit the first priority lower than one we've matched
-            // TODO if isn't child + higher prio match don't run it
+            let matched: bool = match (trace.as_deref_mut(), &concurrent_matches) {
+                (Some(trace), _) => {
+                    let (matched, conditions) = trace_magic(input, magic_rule, self.magic_regexes.rule(idx, magic_rule));
 
-            // If the magic succeeds add to the possible types list
-            if run_magic(buf, magic_rule) {
-                possible_types.push(magic_rule.media_type.clone());
+                    trace.push(MagicRuleTrace::new(magic_rule, if matched {
+                        MagicRuleOutcome::Matched(conditions)
+                    } else {
+                        MagicRuleOutcome::NotMatched(conditions)
+                    }));
 
-                last_match_priority = magic_rule.priority;
+                    matched
+                }
+                (None, Some(concurrent_matches)) => concurrent_matches[idx],
+                (None, None) => run_magic(input, magic_rule, self.magic_regexes.rule(idx, magic_rule))
+            };
+
+            // If the magic succeeds add to the possible types list
+            if matched {
+                possible_types.push(magic_rule);
             }
         }
 
         possible_types
     }
 
-    fn run_glob_rules(&self, resource_name: &String) -> Option<String> {
+    // Skipping depends on earlier matches so threads evaluate every rule up front
+    fn run_magic_concurrently(&self, input: &MagicInput) -> Option<Vec<bool>> {
+        let magic_pool: &MagicPool = self.magic_pool.as_ref()?;
+
+        // The threads are handed a copy of the input so only pass the parts rules compare
+        let head_len: usize = self.read_plan.read_ahead;
+
+        let compared: MagicInput = MagicInput {
+            head: &input.head[..min(input.head.len(), head_len)],
+            tail: &input.tail[input.tail.len().saturating_sub(self.read_behind())..],
+            len: input.len,
+        };
+
+        Some(magic_pool.run(&compared, self.registry.magic_rules.len()))
+    }
+
+    pub(crate) fn is_xml(&self, media_type: &str) -> bool {
+        media_type == XML_MEDIA_TYPE || self.is_canonical_sub_type(XML_MEDIA_TYPE, media_type)
+    }
+
+    fn run_root_xml_rules(&self, media_type: &str, buf: &[u8]) -> Option<String> {
+        // Only XML documents have a root element to inspect
+        if !self.is_xml(media_type) {
+            return None;
+        }
+
+        let root_element: RootElement = extract_root_element(buf)?;
+
+        debug!("Found root element {} in namespace {:?}", root_element.local_name, root_element.namespace_uri);
+
+        self.registry.root_xml_rules.iter()
+            .find(|root_xml_rule| run_root_xml(&root_element, root_xml_rule))
+            .map(|root_xml_rule| root_xml_rule.media_type.to_string())
+    }
+
+    fn run_glob_rules(&self, resource_name: &str) -> Option<String> {
         // TODO parsing of HTTP paths etc
-        // Only bother if the filename has an extension
+        let lower_case_name: String = resource_name.to_lowercase();
+
+        // Cutting the name at each dot in turn finds the longest extension first
+        let extension_matches = lower_case_name.match_indices(EXTENSION_DOT)
+            .find_map(|(idx, _)| self.registry.glob_extensions.get(&lower_case_name[idx + 1..]))
+            .into_iter()
+            .flat_map(|glob_rules| glob_rules.iter());
+
+        let pattern_matches = self.registry.glob_rules.iter().enumerate()
+            .filter(|(idx, glob_rule)| matches_glob(resource_name, &lower_case_name, glob_rule, self.glob_regexes.get(*idx)))
+            .map(|(_, glob_rule)| glob_rule);
+
+        extension_matches
+            .chain(pattern_matches)
+            .fold(None, |best: Option<&GlobRule>, glob_rule| match best {
+                Some(best) if !self.outranks(resource_name, glob_rule, best) => Some(best),
+                _ => Some(glob_rule),
+            })
+            .map(|glob_rule| glob_rule.media_type.to_string())
+    }
+
+    // As in Tika a glob shared with a parent type belongs to the more specific type, otherwise the earlier glob wins
+    fn outranks(&self, resource_name: &str, glob_rule: &GlobRule, other: &GlobRule) -> bool {
+        match glob_rank(resource_name, glob_rule).cmp(&glob_rank(resource_name, other)) {
+            Ordering::Equal => self.is_canonical_sub_type(&other.media_type, &glob_rule.media_type),
+            ordering => ordering == Ordering::Greater,
+        }
+    }
 
-        resource_name.split_once(EXTENSION_DOT).and_then(|(_, _ext)| {
-            for glob_rule in &self.registry.glob_rules {
-                let media_type: &String = &glob_rule.media_type;
+    fn trace_glob_rules(&self, resource_name: &str) -> Vec<GlobTrace> {
+        let lower_case_name: String = resource_name.to_lowercase();
 
-                if run_glob(resource_name, glob_rule) {
-                    return Some(media_type.clone());
-                }
+        // Every extension the name ends with is looked up in the index
+        let extension_matches = lower_case_name.match_indices(EXTENSION_DOT)
+            .filter_map(|(idx, _)| self.registry.glob_extensions.get(&lower_case_name[idx + 1..]))
+            .flat_map(|glob_rules| glob_rules.iter())
+            .map(|glob_rule| GlobTrace::new(glob_rule, true));
+
+        let pattern_matches = self.registry.glob_rules.iter().enumerate()
+            .map(|(idx, glob_rule)| GlobTrace::new(glob_rule,
+                matches_glob(resource_name, &lower_case_name, glob_rule, self.glob_regexes.get(idx))));
+
+        extension_matches.chain(pattern_matches).collect()
+    }
+}
+
+// The start of a stream, read the same way whether the stream is sync or async
+pub(crate) struct PrefixBuffer {
+    buf: Vec<u8>,
+    filled: usize,
+    ended: bool,
+}
+
+impl PrefixBuffer {
+    pub(crate) fn new(len: usize) -> PrefixBuffer {
+        PrefixBuffer { buf: vec![0; len], filled: 0, ended: false }
+    }
+
+    // Where to read to next, until full or the stream has ended
+    pub(crate) fn unfilled(&mut self) -> Option<&mut [u8]> {
+        if self.ended || self.filled == self.buf.len() {
+            return None;
+        }
+
+        Some(&mut self.buf[self.filled..])
+    }
+
+    // A single read may return fewer bytes than are available so keep going until full
+    pub(crate) fn record(&mut self, read: io::Result<usize>) -> io::Result<()> {
+        match read {
+            Ok(0) => self.ended = true,
+            Ok(read) => self.filled += read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn into_prefix(mut self) -> Vec<u8> {
+        // Only bytes from the input may be matched against
+        self.buf.truncate(self.filled);
+
+        self.buf
+    }
+}
+
+// What a pass over the ranges of a seekable input read so far found
+pub(crate) enum SeekablePass {
+    Detected(Option<DetectionResult>),
+    // Read the gaps between the windows and detect again
+    NeedsGaps,
+}
+
+// The parts of a seekable input the rules compare, read the same way whether the input is sync or async
+pub(crate) struct SeekableInput {
+    // Gaps between the windows stay zeroed unless the head is scanned for a root element
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    // Merged ranges of the head to read, in ascending order
+    windows: Vec<Range<usize>>,
+    // Ranges of the head still to read, in ascending order
+    unread: Vec<Range<usize>>,
+    tail_unread: bool,
+    len: u64,
+}
+
+impl SeekableInput {
+    // Where to seek to and the buffer to fill from there for each part not yet read, in the order to read them
+    pub(crate) fn reads(&mut self) -> Vec<(SeekFrom, &mut [u8])> {
+        let unread: Vec<Range<usize>> = std::mem::take(&mut self.unread);
+        let mut reads: Vec<(SeekFrom, &mut [u8])> = split_ranges(&mut self.head, &unread);
+
+        if std::mem::take(&mut self.tail_unread) && !self.tail.is_empty() {
+            reads.push((SeekFrom::End(-(self.tail.len() as i64)), &mut self.tail));
+        }
+
+        reads
+    }
+
+    fn has_gaps(&self) -> bool {
+        self.windows.iter().map(Range::len).sum::<usize>() < self.head.len()
+    }
+
+    // Queue the rest of the head, after which it holds the whole prefix of the input
+    fn read_gaps(&mut self) {
+        let mut gap_start: usize = 0;
+
+        for window in &self.windows {
+            if gap_start < window.start {
+                self.unread.push(gap_start..window.start);
             }
 
-            None
-        })
+            gap_start = window.end;
+        }
+
+        if gap_start < self.head.len() {
+            self.unread.push(gap_start..self.head.len());
+        }
+
+        // One window over the whole head so there are no gaps left
+        self.windows.clear();
+        self.windows.push(0..self.head.len());
+    }
+
+    pub(crate) fn magic_input(&self) -> MagicInput<'_> {
+        MagicInput { head: &self.head, tail: &self.tail, len: Some(self.len) }
+    }
+}
+
+// Split off each range of the buffer in turn, with where it starts in the input
+fn split_ranges<'a>(buf: &'a mut [u8], ranges: &[Range<usize>]) -> Vec<(SeekFrom, &'a mut [u8])> {
+    let mut splits: Vec<(SeekFrom, &mut [u8])> = vec![];
+
+    // Ranges are ascending and don't overlap
+    let mut rest: &mut [u8] = buf;
+    let mut rest_start: usize = 0;
+
+    for range in ranges {
+        let (split, after) = std::mem::take(&mut rest)[range.start - rest_start..].split_at_mut(range.len());
+
+        splits.push((SeekFrom::Start(range.start as u64), split));
+
+        rest = after;
+        rest_start = range.end;
+    }
+
+    splits
+}
+
+// Globs ignore case unless declared case sensitive
+fn matches_glob(resource_name: &str, lower_case_name: &str, glob_rule: &GlobRule, regex: Option<&Regex>) -> bool {
+    let name: &str = if glob_rule.case_sensitive { resource_name } else { lower_case_name };
+
+    run_glob(name, glob_rule, regex)
+}
+
+// Literal names beat patterns, then the longest glob as written,
+// then globs matching in the case they were declared and then case sensitive globs
+fn glob_rank(resource_name: &str, glob_rule: &GlobRule) -> (bool, usize, bool, bool) {
+    (
+        glob_rule.glob_type == GlobType::Exact,
+        glob_rule.declared.len(),
+        matches_declared_case(resource_name, glob_rule),
+        glob_rule.case_sensitive,
+    )
+}
+
+// Type and subtype are case insensitive and parameters are trimmed as in the registry
+fn normalise_media_type(media_type: &str) -> String {
+    let trimmed: String = trim_parameters(media_type);
+
+    match trimmed.split_once(PARAMETER_SEPARATOR) {
+        Some((base, parameters)) => format!("{}{}{}", base.to_ascii_lowercase(), PARAMETER_SEPARATOR, parameters),
+        None => trimmed.to_ascii_lowercase()
     }
 }
\ No newline at end of file
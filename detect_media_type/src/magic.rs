@@ -1,17 +1,70 @@
 use std::cmp::min;
+use std::sync::OnceLock;
 
 use log::{debug, info};
+use memchr::memmem;
+use regex::bytes::Regex;
 
-use rule_def::{MagicRule, Match, Single};
+use rule_def::{anchored_regex, numbered_clauses, MagicRule, Match, MatchValueType, Offset, Single, REGEX_WINDOW};
 
-pub(crate) fn run_magic(buf: &[u8], magic_rule: &MagicRule) -> bool {
-    return magic_rule.conditions.iter().any(|condition: &Match| {
+use crate::explain::{ClauseTrace, ConditionTrace};
+
+// Regex magic compiled the first time each rule is run, indexed like the registry's magic rules
+pub(crate) struct MagicRegexes(Vec<OnceLock<RuleRegexes>>);
+
+impl MagicRegexes {
+    pub(crate) fn new(magic_rules: &[MagicRule]) -> MagicRegexes {
+        MagicRegexes(magic_rules.iter().map(|_| OnceLock::new()).collect())
+    }
+
+    pub(crate) fn rule(&self, idx: usize, magic_rule: &MagicRule) -> &RuleRegexes {
+        self.0[idx].get_or_init(|| RuleRegexes(magic_rule.clauses().into_iter()
+            .map(|clause| match clause.value_type {
+                MatchValueType::Regex => compile_regex(clause),
+                _ => None,
+            })
+            .collect()))
+    }
+}
+
+// The compiled regex clauses of one rule, indexed like the rule's clauses
+pub(crate) struct RuleRegexes(Vec<Option<Regex>>);
+
+impl RuleRegexes {
+    // None for a pattern using syntax the regex crate lacks, which can never match
+    fn get(&self, position: usize) -> Option<&Regex> {
+        self.0.get(position).and_then(Option::as_ref)
+    }
+}
+
+// The parts of an input magic can be evaluated against
+#[derive(Clone, Copy)]
+pub(crate) struct MagicInput<'a> {
+    // Bytes from the start of the input
+    pub head: &'a [u8],
+    // Bytes up to the end of the input
+    pub tail: &'a [u8],
+    // Length of the whole input, None when the end wasn't reached
+    pub len: Option<u64>,
+}
+
+impl<'a> MagicInput<'a> {
+    pub(crate) fn new(buf: &'a [u8], complete: bool) -> MagicInput<'a> {
+        match complete {
+            true => MagicInput { head: buf, tail: buf, len: Some(buf.len() as u64) },
+            false => MagicInput { head: buf, tail: &[], len: None },
+        }
+    }
+}
+
+pub(crate) fn run_magic(input: &MagicInput, magic_rule: &MagicRule, regexes: &RuleRegexes) -> bool {
+    return magic_rule.numbered_conditions().any(|(position, condition): (usize, &Match)| {
         match condition {
             Match::Multi(multi) => {
                 let mut curr_matches: u8 = 0;
 
-                for match_clause in &multi.conditions {
-                    if evaluate_match(buf, match_clause) {
+                for (position, match_clause) in numbered_clauses(position, &multi.conditions) {
+                    if evaluate_match(input, match_clause, position, regexes) {
                         curr_matches += 1;
                     }
 
@@ -22,27 +75,218 @@ pub(crate) fn run_magic(buf: &[u8], magic_rule: &MagicRule) -> bool {
 
                 false
             }
-            Match::Single(single) => evaluate_match(buf, single)
+            Match::Single(single) => evaluate_match(input, single, position, regexes)
         }
     });
 }
 
-fn evaluate_match(buf: &[u8], match_clause: &Single) -> bool {
-    let from: usize = match_clause.offset.from as usize;
+// Trace every clause of a rule rather than stopping at the first match
+pub(crate) fn trace_magic(input: &MagicInput, magic_rule: &MagicRule, regexes: &RuleRegexes) -> (bool, Vec<ConditionTrace>) {
+    let conditions: Vec<ConditionTrace> = magic_rule.numbered_conditions().map(|(position, condition): (usize, &Match)| {
+        match condition {
+            Match::Multi(multi) => {
+                let clauses: Vec<ClauseTrace> = numbered_clauses(position, &multi.conditions)
+                    .map(|(position, match_clause)| trace_match(input, match_clause, position, regexes))
+                    .collect();
+
+                let matched_count: usize = clauses.iter().filter(|clause| clause.matched).count();
+
+                ConditionTrace::Multi {
+                    min_to_match: multi.min_to_match,
+                    matched: matched_count >= usize::from(multi.min_to_match),
+                    clauses,
+                }
+            }
+            Match::Single(single) => ConditionTrace::Single(trace_match(input, single, position, regexes))
+        }
+    }).collect();
+
+    (conditions.iter().any(ConditionTrace::matched), conditions)
+}
+
+fn trace_match(input: &MagicInput, match_clause: &Single, position: usize, regexes: &RuleRegexes) -> ClauseTrace {
+    let matched_at: Option<u64> = match_offset(input, match_clause, regexes.get(position));
+
+    // Children are numbered straight after their parent
+    let children: Vec<ClauseTrace> = numbered_clauses(position + 1, &match_clause.conditions)
+        .map(|(position, child_clause)| trace_match(input, child_clause, position, regexes))
+        .collect();
+
+    ClauseTrace {
+        from: match_clause.offset.from,
+        to: match_clause.offset.end(),
+        from_end: match_clause.offset.from_end,
+        value_type: match_clause.value_type.clone(),
+        matched_at,
+        matched: matched_at.is_some() && (children.is_empty() || children.iter().any(|child| child.matched)),
+        children,
+    }
+}
+
+// Outcome of a rule once more bytes can't change it, None while it could still go either way
+pub(crate) fn decide_magic(input: &MagicInput, magic_rule: &MagicRule, regexes: &RuleRegexes, complete: bool) -> Option<bool> {
+    any_outcome(magic_rule.numbered_conditions().map(|(position, condition): (usize, &Match)| {
+        match condition {
+            Match::Multi(multi) => {
+                let outcomes: Vec<Option<bool>> = numbered_clauses(position, &multi.conditions)
+                    .map(|(position, match_clause)| decide_match(input, match_clause, position, regexes, complete))
+                    .collect();
+
+                let matched: usize = outcomes.iter().filter(|outcome| **outcome == Some(true)).count();
+                let undecided: usize = outcomes.iter().filter(|outcome| outcome.is_none()).count();
+                let min_to_match: usize = usize::from(multi.min_to_match);
+
+                if matched >= min_to_match {
+                    Some(true)
+                } else if matched + undecided < min_to_match {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+            Match::Single(single) => decide_match(input, single, position, regexes, complete)
+        }
+    }))
+}
+
+fn decide_match(input: &MagicInput, match_clause: &Single, position: usize, regexes: &RuleRegexes, complete: bool) -> Option<bool> {
+    let regex: Option<&Regex> = regexes.get(position);
 
-    if buf.len() > from {
-        let to: usize = min(match_clause.bytes.len() + from, buf.len());
-        let slice: &[u8] = &buf[from..to];
+    // A value which has been found stays found as more bytes arrive
+    if match_offset(input, match_clause, regex).is_none() {
+        let window_filled: bool = match_clause.head_range()
+            .is_some_and(|range| range.end <= input.head.len());
 
-        debug!("Comparing buffers {:?} to {:?}", slice, &match_clause.bytes);
+        // An unsupported pattern won't match however many bytes arrive
+        let unsupported: bool = match_clause.value_type == MatchValueType::Regex && regex.is_none();
 
-        slice == match_clause.bytes.as_slice() &&
-            // Either there are no nested conditions
-            (match_clause.conditions.is_empty() ||
-                // Or at least one child condition must match
-                match_clause.conditions.iter().any(|child_clause| evaluate_match(buf, child_clause)))
+        return if complete || window_filled || unsupported { Some(false) } else { None };
+    }
+
+    if match_clause.conditions.is_empty() {
+        return Some(true);
+    }
+
+    any_outcome(numbered_clauses(position + 1, &match_clause.conditions)
+        .map(|(position, child_clause)| decide_match(input, child_clause, position, regexes, complete)))
+}
+
+// Any match decides an OR which otherwise waits until every branch is decided
+fn any_outcome(outcomes: impl Iterator<Item=Option<bool>>) -> Option<bool> {
+    let mut outcome: Option<bool> = Some(false);
+
+    for branch in outcomes {
+        match branch {
+            Some(true) => return Some(true),
+            Some(false) => {}
+            None => outcome = None
+        }
+    }
+
+    outcome
+}
+
+fn evaluate_match(input: &MagicInput, match_clause: &Single, position: usize, regexes: &RuleRegexes) -> bool {
+    match_offset(input, match_clause, regexes.get(position)).is_some() &&
+        // Either there are no nested conditions
+        (match_clause.conditions.is_empty() ||
+            // Or at least one child condition must match
+            numbered_clauses(position + 1, &match_clause.conditions)
+                .any(|(position, child_clause)| evaluate_match(input, child_clause, position, regexes)))
+}
+
+// Find where the value of a clause starts, ignoring any nested conditions
+// Regex clauses need their compiled pattern, other clauses pass None
+fn match_offset(input: &MagicInput, match_clause: &Single, regex: Option<&Regex>) -> Option<u64> {
+    let offset: &Offset = &match_clause.offset;
+
+    if offset.from_end {
+        // The end has to have been reached to count back from it
+        let len: u64 = input.len?;
+        let tail: &[u8] = input.tail;
+
+        // Ranges reaching before the start of short inputs are clipped but the last position must exist
+        let first: usize = tail.len().saturating_sub(offset.from as usize);
+        let last: usize = tail.len().checked_sub(offset.to as usize)?;
+
+        find_between(tail, first, last, match_clause, regex).map(|idx| len - tail.len() as u64 + idx as u64)
     } else {
+        find_between(input.head, offset.from as usize, offset.end() as usize, match_clause, regex).map(|idx| idx as u64)
+    }
+}
+
+// Find a value starting anywhere from the first to the last position
+fn find_between(buf: &[u8], first: usize, last: usize, match_clause: &Single, regex: Option<&Regex>) -> Option<usize> {
+    if buf.len() <= first {
         // If buffer ends before magic starts it can't be a match
-        false
+        return None;
     }
-}
\ No newline at end of file
+
+    if match_clause.value_type != MatchValueType::Regex && buf.len() < first + match_clause.bytes.len() {
+        // Nor can it if the file ends part way through the value
+        return None;
+    }
+
+    // Magic may start anywhere between the offset and the end of its range
+    let end: usize = min(last, buf.len() - 1);
+
+    match match_clause.value_type {
+        MatchValueType::Regex => {
+            let regex: &Regex = regex?;
+
+            (first..=end).find(|start| {
+                let to: usize = min(REGEX_WINDOW + start, buf.len());
+
+                regex.is_match(&buf[*start..to])
+            })
+        }
+        _ => {
+            // Covers every byte of a match starting within the range
+            let to: usize = min(match_clause.bytes.len() + end, buf.len());
+            let window: &[u8] = &buf[first..to];
+
+            debug!("Searching {}..{} for {:?}", first, to, match_clause.bytes);
+
+            find_bytes(window, match_clause).map(|idx| first + idx)
+        }
+    }
+}
+
+fn find_bytes(window: &[u8], match_clause: &Single) -> Option<usize> {
+    if match_clause.bytes.is_empty() {
+        return Some(0);
+    }
+
+    if match_clause.mask.is_none() && match_clause.value_type != MatchValueType::StringIgnoreCase {
+        // Numeric values are encoded in their byte order at build time so search as bytes
+        memmem::find(window, &match_clause.bytes)
+    } else {
+        window.windows(match_clause.bytes.len()).position(|slice| compare_bytes(slice, match_clause))
+    }
+}
+
+fn compare_bytes(slice: &[u8], match_clause: &Single) -> bool {
+    let ignore_case: bool = match_clause.value_type == MatchValueType::StringIgnoreCase;
+
+    match &match_clause.mask {
+        None if ignore_case => slice.eq_ignore_ascii_case(&match_clause.bytes),
+        None => slice == match_clause.bytes.as_ref(),
+        Some(mask) => {
+            // Masks are padded to the length of their value by rule_gen
+            slice.len() == match_clause.bytes.len() &&
+                slice.iter().zip(match_clause.bytes.iter()).zip(mask.iter()).all(|((actual, expected), mask_byte)| {
+                    if ignore_case {
+                        (actual & mask_byte).eq_ignore_ascii_case(&(expected & mask_byte))
+                    } else {
+                        actual & mask_byte == expected & mask_byte
+                    }
+                })
+        }
+    }
+}
+
+// Patterns are validated by rule_gen apart from those it knows are unsupported
+fn compile_regex(match_clause: &Single) -> Option<Regex> {
+    std::str::from_utf8(&match_clause.bytes).ok()
+        .and_then(|pattern| Regex::new(&anchored_regex(pattern)).ok())
+}
@@ -0,0 +1,117 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use rule_def::{MagicRule, MediaTypeRegistry};
+
+use crate::magic::{run_magic, MagicInput, MagicRegexes};
+
+// Threads started with a detector which evaluate every magic rule between them
+// Spawning threads for each input costs more than evaluating the rules, so they're kept for the detector's lifetime
+pub(crate) struct MagicPool {
+    jobs: Vec<Sender<MagicJob>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+// Rules are dealt out in turn so the expensive ones, which tend to share a priority, are spread between threads
+struct MagicJob {
+    input: Arc<OwnedInput>,
+    first: usize,
+    step: usize,
+    results: Sender<(usize, Vec<bool>)>,
+}
+
+// A copy of the input as the threads outlive the caller's borrow
+struct OwnedInput {
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    len: Option<u64>,
+}
+
+impl OwnedInput {
+    fn magic_input(&self) -> MagicInput<'_> {
+        MagicInput { head: &self.head, tail: &self.tail, len: self.len }
+    }
+}
+
+impl MagicPool {
+    pub(crate) fn new(threads: usize, registry: &Arc<MediaTypeRegistry>, magic_regexes: &Arc<MagicRegexes>) -> MagicPool {
+        let (jobs, workers): (Vec<Sender<MagicJob>>, Vec<JoinHandle<()>>) = (0..threads)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel();
+                let registry: Arc<MediaTypeRegistry> = Arc::clone(registry);
+                let magic_regexes: Arc<MagicRegexes> = Arc::clone(magic_regexes);
+
+                (sender, thread::spawn(move || run_jobs(&registry, &magic_regexes, receiver)))
+            })
+            .unzip();
+
+        MagicPool { jobs, workers }
+    }
+
+    pub(crate) fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    // Whether each magic rule matches, indexed like the registry's magic rules
+    pub(crate) fn run(&self, input: &MagicInput, rule_count: usize) -> Vec<bool> {
+        let input: Arc<OwnedInput> = Arc::new(OwnedInput {
+            head: input.head.to_vec(),
+            tail: input.tail.to_vec(),
+            len: input.len,
+        });
+
+        let step: usize = self.jobs.len();
+        let (results, received) = mpsc::channel();
+
+        for (first, jobs) in self.jobs.iter().enumerate() {
+            jobs.send(MagicJob { input: Arc::clone(&input), first, step, results: results.clone() })
+                .expect("Magic rule thread stopped");
+        }
+
+        drop(results);
+
+        let mut matches: Vec<bool> = vec![false; rule_count];
+        let mut finished: usize = 0;
+
+        for (first, job_matches) in received {
+            for (idx, matched) in job_matches.into_iter().enumerate() {
+                matches[first + idx * step] = matched;
+            }
+
+            finished += 1;
+        }
+
+        // A thread which panicked drops its job without answering
+        assert_eq!(finished, step, "Magic rule thread panicked");
+
+        matches
+    }
+}
+
+fn run_jobs(registry: &MediaTypeRegistry, magic_regexes: &MagicRegexes, jobs: Receiver<MagicJob>) {
+    let magic_rules: &[MagicRule] = &registry.magic_rules;
+
+    // Stops once the pool is dropped
+    for job in jobs {
+        let input: MagicInput = job.input.magic_input();
+
+        let matches: Vec<bool> = (job.first..magic_rules.len()).step_by(job.step)
+            .map(|idx| run_magic(&input, &magic_rules[idx], magic_regexes.rule(idx, &magic_rules[idx])))
+            .collect();
+
+        // The caller only stops listening if another thread panicked
+        let _ = job.results.send((job.first, matches));
+    }
+}
+
+impl Drop for MagicPool {
+    fn drop(&mut self) {
+        // Closing the queues lets each thread finish its loop
+        self.jobs.clear();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use rule_def::RootXmlRule;
+
+const BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+const PROCESSING_INSTRUCTION_START: &[u8] = b"<?";
+const PROCESSING_INSTRUCTION_END: &[u8] = b"?>";
+const COMMENT_START: &[u8] = b"<!--";
+const COMMENT_END: &[u8] = b"-->";
+const DECLARATION_START: &[u8] = b"<!";
+const TAG_START: &[u8] = b"<";
+
+const NAMESPACE_ATTRIBUTE: &str = "xmlns";
+
+pub(crate) struct RootElement {
+    pub local_name: String,
+    pub namespace_uri: Option<String>,
+}
+
+pub(crate) fn run_root_xml(root_element: &RootElement, root_xml_rule: &RootXmlRule) -> bool {
+    // Namespaces must agree, including both being absent
+    root_xml_rule.namespace_uri.as_deref() == root_element.namespace_uri.as_deref() &&
+        // A rule without a name matches any element in the namespace
+        root_xml_rule.local_name.as_ref().is_none_or(|local_name| local_name == &root_element.local_name)
+}
+
+// Find the first element of an XML document without fully parsing it
+pub(crate) fn extract_root_element(buf: &[u8]) -> Option<RootElement> {
+    let mut rest: &[u8] = buf.strip_prefix(BYTE_ORDER_MARK).unwrap_or(buf);
+
+    loop {
+        rest = rest.trim_ascii_start();
+
+        // Skip over everything which can precede the root element
+        rest = if let Some(remaining) = rest.strip_prefix(PROCESSING_INSTRUCTION_START) {
+            skip_past(remaining, PROCESSING_INSTRUCTION_END)?
+        } else if let Some(remaining) = rest.strip_prefix(COMMENT_START) {
+            skip_past(remaining, COMMENT_END)?
+        } else if let Some(remaining) = rest.strip_prefix(DECLARATION_START) {
+            skip_declaration(remaining)?
+        } else {
+            // Content before the root element means this isn't XML
+            return parse_start_tag(rest.strip_prefix(TAG_START)?);
+        };
+    }
+}
+
+fn skip_past<'a>(buf: &'a [u8], terminator: &[u8]) -> Option<&'a [u8]> {
+    buf.windows(terminator.len())
+        .position(|window| window == terminator)
+        .map(|idx| &buf[idx + terminator.len()..])
+}
+
+// Doctype declarations may contain an internal subset in square brackets
+fn skip_declaration(buf: &[u8]) -> Option<&[u8]> {
+    let mut depth: usize = 0;
+    let mut quote: Option<u8> = None;
+
+    for (idx, byte) in buf.iter().enumerate() {
+        match (quote, byte) {
+            (Some(open), _) if open == *byte => quote = None,
+            (Some(_), _) => {}
+            (None, b'"' | b'\'') => quote = Some(*byte),
+            (None, b'[') => depth += 1,
+            (None, b']') => depth = depth.saturating_sub(1),
+            (None, b'>') if depth == 0 => return Some(&buf[idx + 1..]),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn is_name_end(byte: &u8) -> bool {
+    byte.is_ascii_whitespace() || matches!(byte, b'/' | b'>' | b'=')
+}
+
+fn parse_start_tag(buf: &[u8]) -> Option<RootElement> {
+    let name_len: usize = buf.iter().position(is_name_end)?;
+    let name: String = String::from_utf8_lossy(&buf[..name_len]).into_owned();
+
+    if name.is_empty() {
+        return None;
+    }
+
+    // Prefixed elements take their namespace from the matching declaration
+    let (namespace_attribute, local_name): (String, String) = match name.split_once(':') {
+        Some((prefix, local_name)) => (format!("{}:{}", NAMESPACE_ATTRIBUTE, prefix), local_name.into()),
+        None => (NAMESPACE_ATTRIBUTE.into(), name),
+    };
+
+    let mut namespace_uri: Option<String> = None;
+    let mut rest: &[u8] = &buf[name_len..];
+
+    loop {
+        rest = rest.trim_ascii_start();
+
+        match rest.first()? {
+            b'>' | b'/' => break,
+            _ => {
+                let (attribute, value, remaining) = parse_attribute(rest)?;
+
+                if attribute == namespace_attribute {
+                    namespace_uri = Some(value).filter(|value| !value.is_empty());
+                }
+
+                rest = remaining;
+            }
+        }
+    }
+
+    Some(RootElement { local_name, namespace_uri })
+}
+
+// Split a quoted attribute from the start of the buffer
+fn parse_attribute(buf: &[u8]) -> Option<(String, String, &[u8])> {
+    let name_len: usize = buf.iter().position(is_name_end)?;
+    let name: String = String::from_utf8_lossy(&buf[..name_len]).into_owned();
+
+    let rest: &[u8] = buf[name_len..].trim_ascii_start().strip_prefix(b"=")?.trim_ascii_start();
+
+    let quote: u8 = *rest.first().filter(|quote| matches!(quote, b'"' | b'\''))?;
+    let value_len: usize = rest[1..].iter().position(|byte| *byte == quote)?;
+    let value: String = String::from_utf8_lossy(&rest[1..value_len + 1]).into_owned();
+
+    Some((name, value, &rest[value_len + 2..]))
+}
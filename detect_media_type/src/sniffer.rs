@@ -0,0 +1,158 @@
+use std::cmp::min;
+
+use rule_def::{MagicRule, MediaTypeRegistry};
+
+use crate::magic::{decide_magic, MagicInput, RuleRegexes};
+use crate::root_xml::extract_root_element;
+use crate::{DetectionResult, MediaTypeDetector};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SniffState {
+    // More bytes could still change the answer
+    Pending,
+    Decided(DetectionResult),
+    // Every rule has been decided without finding a type
+    Exhausted,
+}
+
+// Detects the type of an input as it arrives in chunks, deciding as soon as more bytes can't help
+pub struct Sniffer<'a> {
+    detector: &'a MediaTypeDetector,
+    resource_name: Option<String>,
+    buf: Vec<u8>,
+    read_ahead: usize,
+    // The last bytes pushed, for rules counted from the end once the input is finished
+    tail: Vec<u8>,
+    read_behind: usize,
+    // Every byte pushed including those beyond the read ahead
+    len: u64,
+    // Rules counted from the end of the input are left until it's finished
+    counted_from_end: Vec<bool>,
+    // Outcome of every magic rule which more bytes can't change
+    outcomes: Vec<Option<bool>>,
+    state: SniffState,
+}
+
+impl MediaTypeDetector {
+    pub fn sniffer(&self) -> Sniffer<'_> {
+        Sniffer::new(self, None)
+    }
+
+    // Sniff a stream with a name to match globs against once the content is decided
+    pub fn named_sniffer(&self, resource_name: &str) -> Sniffer<'_> {
+        Sniffer::new(self, Some(resource_name.into()))
+    }
+}
+
+impl<'a> Sniffer<'a> {
+    fn new(detector: &'a MediaTypeDetector, resource_name: Option<String>) -> Sniffer<'a> {
+        let magic_rules: &[MagicRule] = &detector.registry.magic_rules;
+
+        Sniffer {
+            detector,
+            resource_name,
+            buf: vec![],
+            read_ahead: detector.read_ahead(),
+            tail: vec![],
+            read_behind: detector.read_behind(),
+            len: 0,
+            counted_from_end: magic_rules.iter().map(|magic_rule| magic_rule.read_behind() > 0).collect(),
+            outcomes: vec![None; magic_rules.len()],
+            state: SniffState::Pending,
+        }
+    }
+
+    // Bytes taken so far, to replay ahead of the rest of the input
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> SniffState {
+        if self.state != SniffState::Pending {
+            return self.state.clone();
+        }
+
+        let wanted: usize = self.read_ahead - self.buf.len();
+
+        self.buf.extend_from_slice(&chunk[..min(wanted, chunk.len())]);
+        self.len += chunk.len() as u64;
+
+        // Only the end of the chunk can be within the read behind of the end of the input
+        self.tail.extend_from_slice(&chunk[chunk.len().saturating_sub(self.read_behind)..]);
+        self.tail.drain(..self.tail.len().saturating_sub(self.read_behind));
+
+        self.state = self.evaluate();
+
+        self.state.clone()
+    }
+
+    // Signal the end of the input, which also lets rules counted from the end run
+    // An empty input can still be named or fall back to the default type
+    pub fn finish(self) -> Option<DetectionResult> {
+        match self.state {
+            SniffState::Decided(result) => Some(result),
+            _ => self.detector.detect_type(&self.resource_name, &MagicInput { head: &self.buf, tail: &self.tail, len: Some(self.len) })
+        }
+    }
+
+    // XML is refined by its root element, which may be further on than the magic looked
+    fn awaits_root_element(&self, last_matched: Option<usize>) -> bool {
+        let registry: &MediaTypeRegistry = &self.detector.registry;
+
+        match last_matched {
+            Some(idx) if !registry.root_xml_rules.is_empty() && self.detector.is_xml(&registry.magic_rules[idx].media_type) =>
+                extract_root_element(&self.buf).is_none(),
+            _ => false,
+        }
+    }
+
+    fn evaluate(&mut self) -> SniffState {
+        let full: bool = self.buf.len() >= self.read_ahead;
+        let input: MagicInput = MagicInput::new(&self.buf, false);
+
+        // Rules are sorted low -> high and the last match wins, so track the last rule still
+        // waiting on bytes and the last which matched
+        let mut last_pending: Option<usize> = None;
+        let mut last_matched: Option<usize> = None;
+
+        if self.detector.config.enable_magic {
+            for (idx, magic_rule) in self.detector.registry.magic_rules.iter().enumerate() {
+                // Any more bytes could move the end so these are undecided until finished
+                if self.counted_from_end[idx] {
+                    last_pending = Some(idx);
+
+                    continue;
+                }
+
+                if self.outcomes[idx].is_none() {
+                    // Once the read ahead is full no more bytes will be looked at
+                    let regexes: &RuleRegexes = self.detector.magic_regexes.rule(idx, magic_rule);
+
+                    self.outcomes[idx] = decide_magic(&input, magic_rule, regexes, full);
+                }
+
+                match self.outcomes[idx] {
+                    None => last_pending = Some(idx),
+                    Some(true) => last_matched = Some(idx),
+                    Some(false) => {}
+                }
+            }
+        }
+
+        // Only a rule after the best match could still replace it, even one of equal priority
+        let decided: bool = match (last_pending, last_matched) {
+            (None, _) => true,
+            (Some(pending), Some(matched)) => pending < matched,
+            (Some(_), None) => false,
+        };
+
+        if !decided || (!full && self.awaits_root_element(last_matched)) {
+            return SniffState::Pending;
+        }
+
+        match self.detector.detect_type(&self.resource_name, &input) {
+            Some(result) => SniffState::Decided(result),
+            None => SniffState::Exhausted,
+        }
+    }
+}
@@ -0,0 +1 @@
+﻿<?xml version="1.0"?><foo/>
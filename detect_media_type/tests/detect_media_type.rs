@@ -1,13 +1,16 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 use std::path::Path;
 use detect_media_type;
 
 use std::time::Instant;
 
 use log::{Level, LevelFilter, Metadata, Record};
-use detect_media_type::{DetectorConfig, MediaTypeDetector};
-use rule_def::MediaTypeRegistry;
-use rule_gen::load_type_registry;
+use detect_media_type::{ConditionTrace, DetectError, DetectionResult, DetectionSource, DetectionTrace, DetectorConfig, MagicRuleOutcome, MediaTypeDetector, SniffState, Sniffer};
+use rule_def::{MagicRule, Match, MatchValueType, MediaTypeMetadata, MediaTypeRegistry, Multi, Offset, ReadPlan, Single};
+use rule_gen::{load_type_registry, MAX_READ_AHEAD, READ_PLAN, TYPE_REGISTRY};
 
 pub struct Logger;
 
@@ -27,6 +30,35 @@ impl log::Log for Logger {
 
 static LOG: Logger = Logger;
 
+// Detector over the compiled rules, most tests only change a few config fields from the defaults
+fn detector(config: DetectorConfig) -> MediaTypeDetector {
+    MediaTypeDetector::new(load_type_registry(), config)
+}
+
+// Detector over the compiled rules with a test's own appended, so they're evaluated last and win over earlier matches
+fn detector_with_rules(magic_rules: Vec<MagicRule>) -> MediaTypeDetector {
+    let mut registry: MediaTypeRegistry = load_type_registry();
+    registry.magic_rules.to_mut().extend(magic_rules);
+
+    MediaTypeDetector::new(registry, DetectorConfig::default())
+}
+
+// A rule with one condition, which may be a minShouldMatch group
+fn magic_rule(media_type: &str, priority: u8, condition: Match) -> MagicRule {
+    MagicRule { media_type: media_type.to_string().into(), priority, conditions: vec![condition].into() }
+}
+
+// A clause with no mask, along with any nested clauses it ORs
+fn single(offset: &str, value_type: MatchValueType, value: &'static [u8], conditions: Vec<Single>) -> Single {
+    Single {
+        offset: Offset::from_attr(Some(offset.into())).unwrap(),
+        value_type,
+        bytes: Cow::Borrowed(value),
+        mask: None,
+        conditions: conditions.into(),
+    }
+}
+
 #[test]
 fn it_works() {
     log::set_logger(&LOG)
@@ -37,19 +69,14 @@ fn it_works() {
 
     let registry: MediaTypeRegistry = load_type_registry();
 
-    println!("Loaded {:?} magic and {:?} globs in {:?}",
-             registry.magic_rules.len(), registry.glob_rules.len(), start.elapsed());
-
-    let detector = MediaTypeDetector {
-        registry,
-        config: DetectorConfig {
-            enable_glob: false,
-            enable_magic: false,
-            prioritise_glob: false,
-            max_concurrency: 0,
-            default_type: "",
-        },
-    };
+    // Loading borrows the compiled rules rather than building them
+    assert!(matches!(registry.magic_rules, Cow::Borrowed(_)));
+    assert!(matches!(registry.glob_rules, Cow::Borrowed(_)));
+
+    println!("Loaded {:?} magic, {:?} extension and {:?} other globs in {:?}",
+             registry.magic_rules.len(), registry.glob_extensions.len(), registry.glob_rules.len(), start.elapsed());
+
+    let detector = MediaTypeDetector::new(registry, DetectorConfig::default());
 
     let mut expected_types: HashMap<String, Option<String>> = HashMap::new();
 
@@ -58,6 +85,23 @@ fn it_works() {
     expected_types.insert("image.png".into(), Some("image/png".into()));
     expected_types.insert("image_png".into(), Some("image/png".into()));
     expected_types.insert("file.json".into(), Some("application/json".into()));
+    expected_types.insert("audio_x_dec_adpcm".into(), Some("audio/x-dec-adpcm".into()));
+    expected_types.insert("image_vnd_dgn".into(), Some("image/vnd.dgn;version=7".into()));
+    expected_types.insert("bibtex".into(), Some("application/x-bibtex-text-file".into()));
+    expected_types.insert("archive_bzip2".into(), Some("application/x-bzip2".into()));
+    expected_types.insert("document_pdf".into(), Some("application/pdf".into()));
+    expected_types.insert("document_msword".into(), Some("application/msword".into()));
+    expected_types.insert("robots".into(), Some("text/x-robots".into()));
+    expected_types.insert("feed_atom".into(), Some("application/atom+xml".into()));
+    expected_types.insert("Makefile".into(), Some("text/x-makefile".into()));
+    expected_types.insert("i_investigation.txt".into(), Some("application/x-isatab-investigation".into()));
+    expected_types.insert("PHOTO.JPG".into(), Some("image/jpeg".into()));
+    expected_types.insert("notes.ad.txt".into(), Some("text/x-asciidoc".into()));
+    expected_types.insert("report.docx".into(), Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document".into()));
+    expected_types.insert("evil.jpg".into(), Some("application/x-msdownload".into()));
+    expected_types.insert("image_iso9660".into(), Some("application/x-iso9660-image".into()));
+    // Too short to hold the version field so mustn't match against padding
+    expected_types.insert("truncated_wordperfect".into(), Some("application/vnd.wordperfect".into()));
 
     for (file_name, media_type) in expected_types {
         let after_load = Instant::now();
@@ -66,10 +110,587 @@ fn it_works() {
 
         let path = Path::new(str.as_str());
 
-        let option = detector.detect_file_type(path);
+        let option = detector.detect_file_type(path).unwrap().map(|result| result.media_type);
 
         println!("Matched {:?} in {:?}", option, after_load.elapsed());
 
         assert_eq!(option, media_type);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn resolves_aliases() {
+    let detector = detector(DetectorConfig { enable_glob: false, enable_magic: false, ..Default::default() });
+
+    assert_eq!(detector.canonical_type("text/javascript"), "application/javascript");
+    assert_eq!(detector.canonical_type("Application/X-Gnumeric-Spreadsheet"), "application/x-gnumeric");
+    assert_eq!(detector.canonical_type("text/javascript; charset=UTF-8"), "application/javascript;charset=UTF-8");
+    assert_eq!(detector.canonical_type("image/png"), "image/png");
+    // Canonical names aren't always lower case
+    assert_eq!(detector.canonical_type("application/vnd.ms-visio.drawing.macroenabled.12"),
+        "application/vnd.ms-visio.drawing.macroEnabled.12");
+    assert_eq!(detector.canonical_type("application/x-msdownload;format=pe-armLE"), "application/x-msdownload;format=pe-armLE");
+
+    assert!(detector.is_sub_type("text/plain", "text/javascript"));
+    // Parent declared via the text/x-c alias of text/x-csrc
+    assert!(detector.is_sub_type("text/x-csrc", "image/x-xbitmap"));
+    assert!(!detector.is_sub_type("text/javascript", "text/plain"));
+    assert!(detector.is_sub_type("application/x-tika-visio-ooxml", "application/vnd.ms-visio.drawing.macroEnabled.12"));
+    assert!(detector.is_sub_type("application/x-msdownload;format=pe", "application/x-msdownload;format=pe-armLE"));
+
+    // Declared as "application/onenote; format=package" but registered without the padding
+    let onenote_package: String = detector.detect_name_type("notes.onepkg").unwrap();
+
+    assert_eq!(onenote_package, "application/onenote;format=package");
+    assert_eq!(detector.canonical_type("application/onenote; format=package"), onenote_package);
+    assert_eq!(detector.metadata(&onenote_package).unwrap().description.as_deref(), Some("OneNote Package"));
+    assert!(detector.is_sub_type("application/vnd.ms-cab-compressed", &onenote_package));
+    assert!(detector.is_sub_type("application/vnd.ms-cab-compressed", "application/onenote; format=package"));
+}
+
+#[test]
+fn looks_up_metadata() {
+    let detector = detector(DetectorConfig { enable_glob: false, enable_magic: false, ..Default::default() });
+
+    let png: &MediaTypeMetadata = detector.metadata("image/png").unwrap();
+
+    assert_eq!(png.description.as_deref(), Some("Portable Network Graphics"));
+    assert_eq!(png.acronym.as_deref(), Some("PNG"));
+
+    let jar: &MediaTypeMetadata = detector.metadata("application/java-archive").unwrap();
+
+    assert_eq!(jar.description.as_deref(), Some("Java Archive"));
+    assert_eq!(jar.links, vec!["http://en.wikipedia.org/wiki/.jar".to_string()]);
+    assert_eq!(jar.uti.as_deref(), Some("com.sun.java-archive"));
+
+    let visio: &MediaTypeMetadata = detector.metadata("application/vnd.ms-visio.drawing.macroEnabled.12").unwrap();
+
+    assert_eq!(visio.description.as_deref(), Some("Office Open XML Visio Drawing (macro-enabled)"));
+
+    // Multi line comments are collapsed onto one line
+    assert_eq!(detector.metadata("application/x-quattro-pro").unwrap().description.as_deref(),
+               Some("Quattro Pro - Corel Spreadsheet (part of WordPerfect Office suite)"));
+    assert_eq!(detector.metadata("application/x-unknown"), None);
+}
+
+#[test]
+fn detects_name_type() {
+    let detector = detector(DetectorConfig { enable_glob: false, enable_magic: false, ..Default::default() });
+
+    assert_eq!(detector.detect_name_type("photo.final.PNG"), Some("image/png".into()));
+    assert_eq!(detector.detect_name_type("heap.hprof.txt"), Some("application/vnd.java.hprof.text".into()));
+    assert_eq!(detector.detect_name_type("backup-gz"), Some("application/gzip".into()));
+    assert_eq!(detector.detect_name_type("makefile"), Some("text/x-makefile".into()));
+    // Globs differing only in case go to the one declared in the name's case
+    assert_eq!(detector.detect_name_type("foo.c"), Some("text/x-csrc".into()));
+    assert_eq!(detector.detect_name_type("foo.C"), Some("text/x-c++src".into()));
+    assert_eq!(detector.detect_name_type("foo.h"), Some("text/x-chdr".into()));
+    assert_eq!(detector.detect_name_type("foo.H"), Some("text/x-c++hdr".into()));
+    // Globs shared by a type and its parent belong to the more specific type
+    assert_eq!(detector.detect_name_type("budget.123"), Some("application/vnd.lotus-1-2-3;version=97+9.x".into()));
+    // The wildcard glob is longer as written than *.txt
+    assert_eq!(detector.detect_name_type("i_study.txt"), Some("application/x-isatab-investigation".into()));
+    assert_eq!(detector.detect_name_type("no_extension"), None);
+}
+
+#[test]
+fn honours_config() {
+    let detect = |config: DetectorConfig, file_name: &str| {
+        detector(config).detect_file_type(Path::new(format!("./tests/data/{}", file_name).as_str()))
+            .unwrap()
+            .map(|result| result.media_type)
+    };
+
+    let config = |enable_glob: bool, enable_magic: bool, prioritise_glob: bool, max_concurrency: u8, default_type: &'static str| {
+        DetectorConfig { enable_glob, enable_magic, prioritise_glob, max_concurrency, default_type, ..Default::default() }
+    };
+
+    // A PDF with a text extension
+    assert_eq!(detect(config(true, true, true, 0, ""), "report.txt"), Some("text/plain".into()));
+    assert_eq!(detect(config(true, true, false, 0, ""), "report.txt"), Some("application/pdf".into()));
+    assert_eq!(detect(config(true, false, false, 0, ""), "report.txt"), Some("text/plain".into()));
+    assert_eq!(detect(config(false, true, true, 0, ""), "report.txt"), Some("application/pdf".into()));
+
+    // Placeholder content only matches by name
+    assert_eq!(detect(config(false, true, true, 0, ""), "PHOTO.JPG"), None);
+    assert_eq!(detect(config(false, true, true, 0, "application/octet-stream"), "PHOTO.JPG"),
+               Some("application/octet-stream".into()));
+    assert_eq!(detect(config(false, false, false, 0, "application/octet-stream"), "image_png"),
+               Some("application/octet-stream".into()));
+
+    // Threads are started once with the detector, never more than there are cores to run them
+    let cores: usize = std::thread::available_parallelism().unwrap().get();
+    let threaded = detector(config(false, true, false, 4, ""));
+
+    assert_eq!(threaded.magic_threads(), if cores > 1 { cores.min(4) } else { 0 });
+    assert_eq!(detector(config(false, true, false, 1, "")).magic_threads(), 0);
+
+    // Running magic across threads gives the same results, down to the candidates, even for callers sharing the detector
+    let sequential = detector(config(false, true, false, 0, ""));
+    let mut inputs: Vec<Vec<u8>> = std::fs::read_dir("./tests/data").unwrap()
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    inputs.retain(|input| !input.is_empty());
+
+    std::thread::scope(|scope| {
+        for _ in 0..3 {
+            scope.spawn(|| for input in &inputs {
+                assert_eq!(threaded.detect_bytes(input).unwrap(), sequential.detect_bytes(input).unwrap());
+                assert_eq!(threaded.detect_seekable(Cursor::new(input)).unwrap(), sequential.detect_seekable(Cursor::new(input)).unwrap());
+            });
+        }
+    });
+}
+
+#[test]
+fn describes_detection() {
+    let detector = detector(DetectorConfig { default_type: "application/octet-stream", ..Default::default() });
+
+    let detect = |file_name: &str| -> DetectionResult {
+        detector.detect_file_type_or_panic(Path::new(format!("./tests/data/{}", file_name).as_str())).unwrap()
+    };
+
+    // The name narrows down the generic OOXML container found by magic
+    let docx: DetectionResult = detect("report.docx");
+
+    assert_eq!(docx.source, DetectionSource::Hint);
+    assert_eq!(docx.priority, Some(50));
+    assert_eq!(docx.candidates[..2], ["application/vnd.openxmlformats-officedocument.wordprocessingml.document".to_string(),
+        "application/x-tika-ooxml".to_string()]);
+    assert!(!docx.disagreed);
+
+    let exe: DetectionResult = detect("evil.jpg");
+
+    assert_eq!(exe.source, DetectionSource::Magic);
+    assert_eq!(exe.candidates, vec!["application/x-msdownload".to_string(), "image/jpeg".to_string()]);
+    assert!(exe.disagreed);
+
+    // The BOM rule for text/plain matched at a lower priority than XML so can't be specialised by the name
+    let bom_xml: DetectionResult = detect("bom_xml.c");
+
+    assert_eq!(bom_xml.media_type, "application/xml");
+    assert_eq!(bom_xml.source, DetectionSource::Magic);
+    assert!(bom_xml.candidates.contains(&"text/plain".to_string()));
+    assert!(bom_xml.disagreed);
+
+    let photo: DetectionResult = detect("PHOTO.JPG");
+
+    assert_eq!(photo.source, DetectionSource::Glob);
+    assert_eq!(photo.priority, None);
+    assert_eq!(photo.candidates, vec!["image/jpeg".to_string()]);
+
+    assert_eq!(detect("unknown_data"), DetectionResult {
+        media_type: "application/octet-stream".into(),
+        source: DetectionSource::Fallback,
+        priority: None,
+        candidates: vec![],
+        disagreed: false,
+    });
+}
+
+#[test]
+fn explains_detection() {
+    let detector = detector(DetectorConfig::default());
+
+    let buf: Vec<u8> = std::fs::read("./tests/data/report.docx").unwrap();
+
+    let trace: DetectionTrace = detector.explain_type(Some("report.docx"), &buf);
+
+    assert_eq!(trace.result, detector.detect_file_type(Path::new("./tests/data/report.docx")).unwrap());
+
+    let ooxml: &MagicRuleOutcome = trace.magic_rules.iter()
+        .find(|magic_rule| magic_rule.media_type == "application/x-tika-ooxml")
+        .map(|magic_rule| &magic_rule.outcome)
+        .unwrap();
+
+    // The zip header matched along with the entry name nested under it
+    match ooxml {
+        MagicRuleOutcome::Matched(conditions) => match &conditions[0] {
+            ConditionTrace::Single(clause) => {
+                assert_eq!(clause.matched_at, Some(0));
+                assert_eq!(clause.children[0].matched_at, Some(30));
+                assert_eq!(clause.children[1].matched_at, None);
+            }
+            condition => panic!("Unexpected condition {:?}", condition),
+        },
+        outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+
+    // The generic zip rule adds nothing once the more specific container matched
+    assert!(trace.magic_rules.iter().any(|magic_rule| magic_rule.media_type == "application/zip" &&
+        matches!(&magic_rule.outcome, MagicRuleOutcome::SkippedBySubType(child) if child == "application/x-tika-ooxml")));
+
+    assert!(trace.magic_rules.iter().any(|magic_rule| magic_rule.media_type == "image/png" &&
+        matches!(magic_rule.outcome, MagicRuleOutcome::NotMatched(_))));
+    // There's no priority cutoff so every rule has an outcome
+    assert_eq!(trace.magic_rules.len(), detector.registry().magic_rules.len());
+
+    assert!(trace.globs.iter().any(|glob| glob.pattern == ".docx" && glob.matched));
+    assert!(trace.globs.iter().all(|glob| glob.pattern == ".docx" || !glob.matched));
+}
+
+#[test]
+fn reports_detect_errors() {
+    let detector = detector(DetectorConfig { default_type: "application/octet-stream", ..Default::default() });
+
+    match detector.detect_file_type(Path::new("./tests/data/missing")) {
+        Err(DetectError::Io(error)) => assert_eq!(error.kind(), std::io::ErrorKind::NotFound),
+        result => panic!("Unexpected result {:?}", result),
+    }
+
+    assert!(matches!(detector.detect_file_type(Path::new("./tests/data")), Err(DetectError::NotARegularFile(_))));
+    assert!(matches!(detector.detect_file_type(Path::new("./tests/data/empty")), Err(DetectError::EmptyInput)));
+}
+
+#[test]
+fn reads_ahead_far_enough() {
+    let capped = |max_read_ahead: usize| detector(DetectorConfig { enable_glob: false, max_read_ahead, ..Default::default() });
+
+    // The generated size covers every rule, including the furthest ISO 9660 signature at 36865
+    let furthest: usize = load_type_registry().magic_rules.iter().map(MagicRule::read_ahead).max().unwrap();
+
+    assert_eq!(MAX_READ_AHEAD, furthest);
+    assert_eq!(READ_PLAN, ReadPlan::new(&TYPE_REGISTRY.magic_rules));
+    assert_eq!(capped(0).read_ahead(), MAX_READ_AHEAD);
+    assert_eq!(capped(usize::MAX).read_ahead(), MAX_READ_AHEAD);
+    assert_eq!(capped(1024).read_ahead(), 1024);
+
+    // Capping the read ahead trades signatures deep in the file for latency
+    let iso: &Path = Path::new("./tests/data/image_iso9660");
+
+    assert_eq!(capped(0).detect_file_type(iso).unwrap().map(|result| result.media_type),
+               Some("application/x-iso9660-image".into()));
+    assert_eq!(capped(1024).detect_file_type(iso).unwrap().map(|result| result.media_type), None);
+
+    // Custom rules looking further than the compiled ones are read far enough by every entry point
+    let detector = detector_with_rules(vec![
+        magic_rule("x-test/deep", 255, Match::Single(single("40000", MatchValueType::String, b"DEEP", vec![]))),
+    ]);
+
+    let mut deep: Vec<u8> = vec![0; 40000];
+    deep.extend(b"DEEP and the rest");
+
+    assert_eq!(detector.read_ahead(), 40004);
+
+    let deep_type = Some("x-test/deep".to_string());
+
+    assert_eq!(detector.detect_bytes(&deep).unwrap().map(|result| result.media_type), deep_type);
+    assert_eq!(detector.detect_reader(deep.as_slice()).unwrap().map(|result| result.media_type), deep_type);
+    assert_eq!(detector.detect_seekable(Cursor::new(&deep)).unwrap().map(|result| result.media_type), deep_type);
+
+    let mut sniffer: Sniffer = detector.sniffer();
+    sniffer.push(&deep);
+
+    assert_eq!(sniffer.finish().map(|result| result.media_type), deep_type);
+}
+
+// Hands out a byte at a time like a slow network stream
+struct TrickleReader<'a>(&'a [u8]);
+
+impl Read for TrickleReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match (self.0.split_first(), buf.first_mut()) {
+            (Some((byte, rest)), Some(first)) => {
+                *first = *byte;
+                self.0 = rest;
+
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn detects_streams() {
+    let detector = detector(DetectorConfig::default());
+
+    let media_type = |result: Result<Option<DetectionResult>, DetectError>| result.unwrap().map(|result| result.media_type);
+
+    let png: Vec<u8> = std::fs::read("./tests/data/image_png").unwrap();
+
+    assert_eq!(media_type(detector.detect_bytes(&png)), Some("image/png".into()));
+    assert_eq!(media_type(detector.detect_reader(BufReader::new(png.as_slice()))), Some("image/png".into()));
+    assert_eq!(media_type(detector.detect_reader(TrickleReader(&png))), Some("image/png".into()));
+
+    let feed: File = File::open("./tests/data/feed_atom").unwrap();
+
+    assert_eq!(media_type(detector.detect_reader(feed)), Some("application/atom+xml".into()));
+
+    // The name is only available from the hint
+    let photo: Vec<u8> = std::fs::read("./tests/data/PHOTO.JPG").unwrap();
+
+    assert_eq!(media_type(detector.detect_reader(photo.as_slice())), None);
+    assert_eq!(media_type(detector.detect_named_reader("PHOTO.JPG", photo.as_slice())), Some("image/jpeg".into()));
+
+    // Endless streams are only read as far as the rules need
+    assert!(detector.detect_reader(std::io::repeat(b'a')).is_ok());
+
+    assert!(matches!(detector.detect_bytes(&[]), Err(DetectError::EmptyInput)));
+    assert!(matches!(detector.detect_reader(std::io::empty()), Err(DetectError::EmptyInput)));
+}
+
+// Counts the bytes pulled from the wrapped input
+struct CountingReader<R> {
+    inner: R,
+    read: usize,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read: usize = self.inner.read(buf)?;
+
+        self.read += read;
+
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn detects_seekable_ranges() {
+    let detector = detector(DetectorConfig::default());
+
+    // A large input only has the ranges the rules compare read from it
+    let mut video: Vec<u8> = std::fs::read("./tests/data/image_png").unwrap();
+    video.resize(16 * 1024 * 1024, 0);
+
+    let mut reader = CountingReader { inner: Cursor::new(video), read: 0 };
+
+    assert_eq!(detector.detect_seekable(&mut reader).unwrap().map(|result| result.media_type), Some("image/png".into()));
+
+    // Only the windows the rules compare are read, not the gaps between them
+    let windows: usize = READ_PLAN.head_windows.iter()
+        .map(|window| window.end.min(detector.read_ahead()).saturating_sub(window.start))
+        .sum();
+
+    assert!(windows < detector.read_ahead());
+    assert_eq!(reader.read, windows + READ_PLAN.read_behind);
+
+    // A root element beyond the first window is still found once the content looks like XML
+    let mut atom: Vec<u8> = b"<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!-- ".to_vec();
+    atom.extend([b'x'; 9000]);
+    atom.extend(b" -->\n<feed xmlns=\"http://www.w3.org/2005/Atom\"><title>News</title></feed>\n");
+
+    let mut reader = CountingReader { inner: Cursor::new(atom.as_slice()), read: 0 };
+
+    assert_eq!(detector.detect_seekable(&mut reader).unwrap().map(|result| result.media_type), Some("application/atom+xml".into()));
+    assert_eq!(reader.read, atom.len());
+    assert_eq!(detector.detect_bytes(&atom).unwrap().map(|result| result.media_type), Some("application/atom+xml".into()));
+
+    // A zip's end of central directory follows a comment of up to 65535 bytes
+    let end_of_central_directory: Single = single("-65557:-22", MatchValueType::String, b"PK\x05\x06", vec![]);
+
+    let detector = detector_with_rules(vec![magic_rule("application/zip", 50, Match::Single(end_of_central_directory))]);
+
+    // Only the loaded copy takes the rule, the static registry is left alone
+    assert_eq!(detector.registry().magic_rules.len(), TYPE_REGISTRY.magic_rules.len() + 1);
+
+    let mut archive: Vec<u8> = vec![b'x'; 100_000];
+    archive.extend(b"PK\x05\x06");
+    archive.extend([0; 18]);
+
+    let mut reader = CountingReader { inner: Cursor::new(archive.as_slice()), read: 0 };
+
+    assert_eq!(detector.detect_seekable(&mut reader).unwrap().map(|result| result.media_type), Some("application/zip".into()));
+    assert!(reader.read <= detector.read_ahead() + 65557);
+
+    // Streams only know where the end is once they've been read in full
+    assert_eq!(detector.detect_reader(archive.as_slice()).unwrap(), None);
+    assert_eq!(detector.detect_bytes(&archive).unwrap().map(|result| result.media_type), Some("application/zip".into()));
+
+    // Short inputs only need room for the record nearest the end
+    assert_eq!(detector.detect_bytes(&archive[100_000 - 10..]).unwrap().map(|result| result.media_type), Some("application/zip".into()));
+    assert_eq!(detector.detect_bytes(&archive[100_010..]).unwrap(), None);
+
+    assert!(Offset::from_attr(Some("-22:4".into())).is_err());
+    assert!(Offset::from_attr(Some("-22:-30".into())).is_err());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn detects_async_readers() {
+    use tokio::io::AsyncReadExt;
+
+    let detector = detector(DetectorConfig::default());
+
+    let png: Vec<u8> = tokio::fs::read("./tests/data/image_png").await.unwrap();
+    let mut reader: &[u8] = png.as_slice();
+
+    let sniffed = detector.detect_reader_async(&mut reader).await.unwrap();
+
+    assert_eq!(sniffed.result.map(|result| result.media_type), Some("image/png".into()));
+    assert_eq!(sniffed.prefix.len(), detector.read_ahead());
+
+    // Nothing is lost when the prefix is put back in front of the rest of the stream
+    let mut replayed: Vec<u8> = vec![];
+    AsyncReadExt::chain(sniffed.prefix.as_slice(), reader).read_to_end(&mut replayed).await.unwrap();
+
+    assert_eq!(replayed, png);
+
+    let photo: Vec<u8> = tokio::fs::read("./tests/data/PHOTO.JPG").await.unwrap();
+    let sniffed = detector.detect_named_reader_async("PHOTO.JPG", photo.as_slice()).await.unwrap();
+
+    assert_eq!(sniffed.result.map(|result| result.media_type), Some("image/jpeg".into()));
+    assert_eq!(sniffed.prefix, photo);
+
+    let iso = tokio::fs::File::open("./tests/data/image_iso9660").await.unwrap();
+
+    assert_eq!(detector.detect_seekable_async(iso).await.unwrap().map(|result| result.media_type),
+               Some("application/x-iso9660-image".into()));
+
+    let feed = tokio::fs::File::open("./tests/data/feed_atom").await.unwrap();
+
+    assert_eq!(detector.detect_named_seekable_async("feed_atom", feed).await.unwrap().map(|result| result.media_type),
+               Some("application/atom+xml".into()));
+
+    assert!(matches!(detector.detect_reader_async(tokio::io::empty()).await, Err(DetectError::EmptyInput)));
+}
+
+#[test]
+fn sniffs_chunks() {
+    let detector = detector(DetectorConfig::default());
+
+    // Feed a file a few bytes at a time until the sniffer stops asking for more
+    let sniff = |sniffer: &mut Sniffer, buf: &[u8]| -> (SniffState, usize) {
+        let mut pushed: usize = 0;
+
+        for chunk in buf.chunks(16) {
+            let state: SniffState = sniffer.push(chunk);
+            pushed += chunk.len();
+
+            if state != SniffState::Pending {
+                return (state, pushed);
+            }
+        }
+
+        (SniffState::Pending, buf.len())
+    };
+
+    // Decided once no higher priority rule can still match, well before reading everything rules could need
+    for file_name in ["image_png", "image_jpeg", "image_iso9660"] {
+        let buf: Vec<u8> = std::fs::read(format!("./tests/data/{}", file_name)).unwrap();
+        let mut sniffer: Sniffer = detector.sniffer();
+
+        let (state, pushed) = sniff(&mut sniffer, &buf);
+
+        assert_eq!(state, SniffState::Decided(detector.detect_bytes(&buf).unwrap().unwrap()));
+        assert!(pushed < detector.read_ahead());
+        assert_eq!(sniffer.buffered(), &buf[..pushed]);
+
+        // Later chunks are ignored once decided
+        assert_eq!(sniffer.push(b"more"), state);
+    }
+
+    // Inputs shorter than the rules can look are only decided when finished
+    for file_name in ["document_pdf", "feed_atom", "truncated_wordperfect"] {
+        let buf: Vec<u8> = std::fs::read(format!("./tests/data/{}", file_name)).unwrap();
+        let mut sniffer: Sniffer = detector.sniffer();
+
+        assert_eq!(sniff(&mut sniffer, &buf).0, SniffState::Pending);
+        assert_eq!(sniffer.finish(), detector.detect_bytes(&buf).unwrap());
+    }
+
+    let mut sniffer: Sniffer = detector.named_sniffer("PHOTO.JPG");
+
+    assert_eq!(sniffer.push(b"placeholder\n"), SniffState::Pending);
+    assert_eq!(sniffer.finish().map(|result| result.media_type), Some("image/jpeg".into()));
+
+    assert_eq!(detector.sniffer().finish(), None);
+
+    // An empty input still falls back to its name or the default type
+    assert_eq!(detector.named_sniffer("PHOTO.JPG").finish().map(|result| result.media_type), Some("image/jpeg".into()));
+    assert_eq!(MediaTypeDetector::new(load_type_registry(), DetectorConfig { default_type: "application/octet-stream", ..Default::default() })
+        .sniffer().finish().map(|result| result.media_type), Some("application/octet-stream".into()));
+
+    // Nothing can match once the read ahead is full of bytes no rule looks for
+    let mut sniffer: Sniffer = detector.sniffer();
+
+    assert_eq!(sniff(&mut sniffer, &vec![0xAB; detector.read_ahead() + 100]).0, SniffState::Exhausted);
+    assert_eq!(sniffer.buffered().len(), detector.read_ahead());
+
+    // XML waits for its root element even once no magic rule can change the answer
+    let mut atom: Vec<u8> = b"<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!-- ".to_vec();
+    atom.extend([b'x'; 9000]);
+    atom.extend(b" -->\n<feed xmlns=\"http://www.w3.org/2005/Atom\"><title>News</title></feed>\n");
+
+    let (state, pushed) = sniff(&mut detector.sniffer(), &atom);
+
+    assert_eq!(state, SniffState::Decided(detector.detect_bytes(&atom).unwrap().unwrap()));
+    assert!(matches!(state, SniffState::Decided(result) if result.media_type == "application/atom+xml"));
+    assert!(pushed > 9000);
+
+    // A later rule of equal priority still outranks an earlier match so has to be waited for
+    let rule = |media_type: &str, offset: &str, value: &'static [u8]|
+        magic_rule(media_type, 255, Match::Single(single(offset, MatchValueType::String, value, vec![])));
+
+    let detector = detector_with_rules(vec![rule("x-test/a", "0", b"QQAB"), rule("x-test/b", "100", b"ZZC")]);
+
+    let mut both: Vec<u8> = b"QQAB".to_vec();
+    both.resize(100, b' ');
+    both.extend(b"ZZC and the rest");
+
+    let (state, _) = sniff(&mut detector.sniffer(), &both);
+
+    assert_eq!(detector.detect_bytes(&both).unwrap().map(|result| result.media_type), Some("x-test/b".into()));
+    assert_eq!(state, SniffState::Decided(detector.detect_bytes(&both).unwrap().unwrap()));
+
+    // A rule counted from the end could outrank any match so has to wait until the input is finished
+    let detector = detector_with_rules(vec![rule("x-test/trailer", "-22", b"PK\x05\x06")]);
+
+    let mut trailed: Vec<u8> = std::fs::read("./tests/data/image_png").unwrap();
+    trailed.extend(b"PK\x05\x06");
+    trailed.resize(trailed.len() + 18, 0);
+
+    let mut sniffer: Sniffer = detector.sniffer();
+
+    assert_eq!(sniff(&mut sniffer, &trailed).0, SniffState::Pending);
+    assert_eq!(sniffer.finish().map(|result| result.media_type), Some("x-test/trailer".into()));
+    assert_eq!(detector.detect_bytes(&trailed).unwrap().map(|result| result.media_type), Some("x-test/trailer".into()));
+}
+
+#[test]
+fn matches_nested_regex_magic() {
+    // Regexes both nested under another clause and following a sibling which has children of its own
+    let header: Single = single("0", MatchValueType::String, b"QQRX", vec![
+        single("4", MatchValueType::String, b"v1", vec![]),
+        single("4:8", MatchValueType::Regex, b"v[0-9]{2}\\.", vec![]),
+    ]);
+    let trailer: Single = single("16", MatchValueType::Regex, b"end[a-z]+", vec![]);
+
+    let detector = detector_with_rules(vec![
+        magic_rule("x-test/regex", 255, Match::Multi(Multi { min_to_match: 2, conditions: vec![header, trailer].into() })),
+    ]);
+
+    let matching: &[u8] = b"QQRX  v42.      endmark";
+
+    assert_eq!(detector.detect_bytes(matching).unwrap().map(|result| result.media_type), Some("x-test/regex".into()));
+
+    // Either regex failing leaves too few clauses matched
+    assert_ne!(detector.detect_bytes(b"QQRX  vXX.      endmark").unwrap().map(|result| result.media_type), Some("x-test/regex".into()));
+    assert_ne!(detector.detect_bytes(b"QQRX  v42.      END").unwrap().map(|result| result.media_type), Some("x-test/regex".into()));
+}
+
+#[test]
+fn matches_unicode_magic() {
+    let detector = detector(DetectorConfig::default());
+
+    let asf = |codec: &[u8]| {
+        let mut bytes: Vec<u8> = vec![0x30, 0x26, 0xB2, 0x75];
+        bytes.resize(64, 0);
+        bytes.extend(codec);
+
+        detector.detect_bytes(&bytes).unwrap().map(|result| result.media_type)
+    };
+
+    let utf_16_le: Vec<u8> = "Windows Media Audio".encode_utf16().flat_map(u16::to_le_bytes).collect();
+
+    // The codec name is only written as UTF-16 so plain text doesn't refine the container
+    assert_eq!(asf(&utf_16_le), Some("audio/x-ms-wma".into()));
+    assert_eq!(asf(b"Windows Media Audio"), Some("video/x-ms-asf".into()));
+}
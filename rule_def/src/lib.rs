@@ -1,18 +1,87 @@
 extern crate serde_derive;
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::iter::FromIterator;
 use serde_derive::Serialize;
+use std::ops::Range;
 use std::str::FromStr;
 
+// Tika lets a regex match across this many bytes from the offset
+pub const REGEX_WINDOW: usize = 8192;
+
+const NEGATIVE_SIGN: char = '-';
+const PARAMETER_SEPARATOR: char = ';';
+
+// Text borrowed from the compiled registry, or owned when rules are built at runtime
+pub type Text = Cow<'static, str>;
+// Lists are likewise static slices once compiled
+pub type List<T> = Cow<'static, [T]>;
+
+// Entries sorted by key so a lookup table can be compiled as static data and binary searched
+#[derive(Clone, Serialize, Debug)]
+pub struct SortedMap<V: Clone + 'static>(pub List<(Text, V)>);
+
+impl<V: Clone> SortedMap<V> {
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.0.binary_search_by(|(entry, _)| entry.as_ref().cmp(key))
+            .ok()
+            .map(|idx| &self.0[idx].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Keys are expected to be unique, as when collecting a HashMap
+impl<V: Clone> FromIterator<(String, V)> for SortedMap<V> {
+    fn from_iter<I: IntoIterator<Item=(String, V)>>(iter: I) -> SortedMap<V> {
+        let mut entries: Vec<(Text, V)> = iter.into_iter()
+            .map(|(key, value)| (Text::from(key), value))
+            .collect();
+
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        SortedMap(entries.into())
+    }
+}
+
 #[derive(Clone, Serialize, Debug)]
 pub struct MediaTypeRegistry {
     // Map of parent types to their children
-    pub sub_types: HashMap<String, Vec<String>>,
+    pub sub_types: SortedMap<List<Text>>,
+
+    // Map of lower case aliases to their canonical type
+    pub aliases: SortedMap<Text>,
 
-    pub glob_rules: Vec<GlobRule>,
+    // Descriptive information for types which have any
+    pub metadata: SortedMap<MediaTypeMetadata>,
 
-    // Magic rules sorted by priority high -> low
-    pub magic_rules: Vec<MagicRule>,
+    // Case insensitive *.ext globs keyed by their lower case extension
+    pub glob_extensions: SortedMap<List<GlobRule>>,
+    // Globs which can't be looked up by extension
+    pub glob_rules: List<GlobRule>,
+
+    // Magic rules sorted by priority low -> high so the last match is the best
+    pub magic_rules: List<MagicRule>,
+
+    // Root element rules to refine XML matches in file order
+    pub root_xml_rules: List<RootXmlRule>,
+}
+
+#[derive(Clone, Serialize, Debug, Default, PartialEq)]
+pub struct MediaTypeMetadata {
+    // Human readable name of the type
+    pub description: Option<Text>,
+    pub acronym: Option<Text>,
+    // Links to specifications or further reading
+    pub links: List<Text>,
+    // Apple Uniform Type Identifier
+    pub uti: Option<Text>,
 }
 
 #[derive(Clone, Serialize, Debug)]
@@ -23,10 +92,99 @@ pub enum Rule {
 
 #[derive(Clone, Serialize, Debug)]
 pub struct MagicRule {
-    pub media_type: String,
+    pub media_type: Text,
     // Priority over other magic rules
     pub priority: u8,
-    pub conditions: Vec<Match>,
+    pub conditions: List<Match>,
+}
+
+impl MagicRule {
+    // Bytes from the start of the input needed to evaluate every condition
+    pub fn read_ahead(&self) -> usize {
+        self.clauses().iter().filter_map(|clause| clause.head_range()).map(|range| range.end).max().unwrap_or(0)
+    }
+
+    // Bytes from the end of the input needed to evaluate every condition
+    pub fn read_behind(&self) -> usize {
+        self.clauses().iter().map(|clause| clause.tail_len()).max().unwrap_or(0)
+    }
+
+    // Every clause of the rule including those nested within others
+    // In pre-order, so a clause's position is the one numbered_conditions and numbered_clauses give it
+    pub fn clauses(&self) -> Vec<&Single> {
+        let mut clauses: Vec<&Single> = vec![];
+
+        for condition in self.conditions.iter() {
+            match condition {
+                Match::Multi(multi) => multi.conditions.iter().for_each(|clause| clause.collect_clauses(&mut clauses)),
+                Match::Single(single) => single.collect_clauses(&mut clauses)
+            }
+        }
+
+        clauses
+    }
+
+    // Conditions along with the position of their first clause
+    pub fn numbered_conditions(&self) -> impl Iterator<Item=(usize, &Match)> {
+        self.conditions.iter().scan(0, |next, condition| {
+            let position: usize = *next;
+
+            *next += match condition {
+                Match::Multi(multi) => multi.conditions.iter().map(Single::clause_count).sum(),
+                Match::Single(single) => single.clause_count()
+            };
+
+            Some((position, condition))
+        })
+    }
+}
+
+// Sibling clauses along with their positions, the first being at the given position
+pub fn numbered_clauses(first: usize, clauses: &[Single]) -> impl Iterator<Item=(usize, &Single)> {
+    clauses.iter().scan(first, |next, clause| {
+        let position: usize = *next;
+
+        *next += clause.clause_count();
+
+        Some((position, clause))
+    })
+}
+
+// Where a set of magic rules compares bytes, so a seekable input only has those parts read
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct ReadPlan {
+    // Merged ranges from the start of the input in ascending order
+    pub head_windows: List<Range<usize>>,
+    // Bytes from the start of the input needed to evaluate every rule
+    pub read_ahead: usize,
+    // Bytes from the end of the input
+    pub read_behind: usize,
+}
+
+impl ReadPlan {
+    pub fn new(magic_rules: &[MagicRule]) -> ReadPlan {
+        let mut ranges: Vec<Range<usize>> = magic_rules.iter()
+            .flat_map(MagicRule::clauses)
+            .filter_map(|clause| clause.head_range())
+            .filter(|range| !range.is_empty())
+            .collect();
+
+        ranges.sort_by_key(|range| range.start);
+
+        let mut head_windows: Vec<Range<usize>> = vec![];
+
+        for range in ranges {
+            match head_windows.last_mut() {
+                Some(window) if range.start <= window.end => window.end = window.end.max(range.end),
+                _ => head_windows.push(range)
+            }
+        }
+
+        let read_ahead: usize = magic_rules.iter().map(MagicRule::read_ahead).max().unwrap_or(0);
+        let read_behind: usize = magic_rules.iter().map(MagicRule::read_behind).max().unwrap_or(0);
+
+        ReadPlan { head_windows: head_windows.into(), read_ahead, read_behind }
+    }
 }
 
 #[derive(Clone, Serialize, Debug)]
@@ -41,8 +199,8 @@ impl Match {
         // Only support child single conditions
         if let Match::Single(single_condition) = condition {
             match self {
-                Match::Multi(multi) => multi.conditions.push(single_condition),
-                Match::Single(single) => single.conditions.push(single_condition)
+                Match::Multi(multi) => multi.conditions.to_mut().push(single_condition),
+                Match::Single(single) => single.conditions.to_mut().push(single_condition)
             }
         }
     }
@@ -52,45 +210,146 @@ impl Match {
 pub struct Multi {
     // Minimum conditions to match
     pub min_to_match: u8,
-    pub conditions: Vec<Single>,
+    pub conditions: List<Single>,
 }
 
 #[derive(Clone, Serialize, Debug)]
 pub struct Single {
     pub offset: Offset,
+    // How the magic value was encoded
+    pub value_type: MatchValueType,
     // A sequence of magic bytes
-    pub bytes: Vec<u8>,
+    pub bytes: List<u8>,
+    // Bits to AND against the buffer and magic bytes before comparing, padded to the length of the bytes
+    pub mask: Option<List<u8>>,
     // Any OR'ed conditions with this magic
-    pub conditions: Vec<Single>,
+    pub conditions: List<Single>,
 }
 
+impl Single {
+    // Bytes from the start of the input this clause may compare, None if counted from the end
+    pub fn head_range(&self) -> Option<Range<usize>> {
+        // A regex may look beyond the length of its pattern
+        let len: usize = if self.value_type == MatchValueType::Regex { REGEX_WINDOW } else { self.bytes.len() };
+
+        match self.offset.from_end {
+            true => None,
+            false => Some(self.offset.from as usize..self.offset.end() as usize + len)
+        }
+    }
+
+    // This clause and every clause nested within it
+    pub fn clause_count(&self) -> usize {
+        1 + self.conditions.iter().map(Single::clause_count).sum::<usize>()
+    }
+
+    fn collect_clauses<'a>(&'a self, clauses: &mut Vec<&'a Single>) {
+        clauses.push(self);
+
+        for clause in self.conditions.iter() {
+            clause.collect_clauses(clauses);
+        }
+    }
+
+    // Bytes from the end of the input this clause may compare
+    pub fn tail_len(&self) -> usize {
+        match self.offset.from_end {
+            true => self.offset.from as usize,
+            false => 0
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
 pub enum MatchValueType {
     String,
-    // TODO support some of these
-    // Regex,
-    // StringIgnoreCase,
-    // UnicodeLe,
-    // UnicodeBe,
-    // Byte,
-    // Host16,
-    // Little16,
-    // Big16,
-    // Host32,
-    // Little32,
-    // Big32,
+    // Strings compared ignoring ASCII case
+    StringIgnoreCase,
+    // Pattern matched against raw bytes from the offset
+    Regex,
+    // Strings encoded as UTF-16 in the given byte order at build time
+    UnicodeLe,
+    UnicodeBe,
+    // Numeric values are encoded into bytes in their byte order at build time
+    Byte,
+    Host16,
+    Little16,
+    Big16,
+    Host32,
+    Little32,
+    Big32,
+}
+
+impl MatchValueType {
+    pub fn from_attr(attr: Option<String>) -> Result<MatchValueType, String> {
+        match attr.as_deref() {
+            // Default to string if unset
+            None | Some("string") => Ok(MatchValueType::String),
+            Some("stringignorecase") => Ok(MatchValueType::StringIgnoreCase),
+            Some("regex") => Ok(MatchValueType::Regex),
+            Some("unicodeLE") => Ok(MatchValueType::UnicodeLe),
+            Some("unicodeBE") => Ok(MatchValueType::UnicodeBe),
+            Some("byte") => Ok(MatchValueType::Byte),
+            Some("host16") => Ok(MatchValueType::Host16),
+            Some("little16") => Ok(MatchValueType::Little16),
+            Some("big16") => Ok(MatchValueType::Big16),
+            Some("host32") => Ok(MatchValueType::Host32),
+            Some("little32") => Ok(MatchValueType::Little32),
+            Some("big32") => Ok(MatchValueType::Big32),
+            // Anything else would be compared as the wrong kind of value
+            Some(value_type) => Err(format!("Unsupported match type {:?}", value_type))
+        }
+    }
+}
+
+// Parameters may be padded with whitespace so drop it to give every spelling of a type one name
+pub fn trim_parameters(media_type: &str) -> String {
+    media_type.split(PARAMETER_SEPARATOR)
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join(&PARAMETER_SEPARATOR.to_string())
+}
+
+// Regex magic is anchored to the offset and matches bytes rather than unicode
+pub fn anchored_regex(pattern: &str) -> String {
+    format!("(?-u)^(?:{})", pattern)
+}
+
+// Regex globs must match the whole resource name
+pub fn full_match_regex(pattern: &str) -> String {
+    format!("^(?:{})$", pattern)
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct RootXmlRule {
+    pub media_type: Text,
+
+    // Any root element name matches if unset
+    pub local_name: Option<Text>,
+    // Root element must have no namespace if unset
+    pub namespace_uri: Option<Text>,
 }
 
 #[derive(Clone, Serialize, Debug)]
 pub struct GlobRule {
-    pub media_type: String,
+    pub media_type: Text,
 
-    pub pattern: String,
+    // Literal text to find or a regex depending on the GlobType
+    // Lower case unless the glob is case sensitive
+    pub pattern: Text,
+    // The glob as written in the rules, longer globs are more specific
+    pub declared: Text,
     pub glob_type: GlobType,
+    pub case_sensitive: bool,
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Debug, PartialEq)]
 pub enum GlobType {
+    // Either declared as a regex or a glob with wildcards in the middle
     Regex,
+    // Glob without any wildcards must match the whole name
+    Exact,
     EndsWith,
     StartsWith,
     Contains,
@@ -100,32 +359,66 @@ pub enum GlobType {
 pub struct Offset {
     // The byte to start at
     pub from: u32,
-    // Count to check to
-    pub count: u32,
+    // The second value of a from:to range, the furthest byte a match may start at
+    // Counts back from the end like from when from_end so is never more than from, zero without a range
+    pub to: u32,
+    // Both values count back from the end of the input, e.g. -22 for a zip's end of central directory
+    pub from_end: bool,
 }
 
 impl Offset {
-    pub fn from_attr(attr: Option<String>) -> Offset {
+    // The last byte a match may start at, or the furthest from the end when from_end
+    // Tika uses the second value of a range as an end rather than a length
+    pub fn end(&self) -> u32 {
+        std::cmp::max(self.from, self.to)
+    }
+
+    pub fn from_attr(attr: Option<String>) -> Result<Offset, String> {
+        let parse = |value: &str| u32::from_str(value)
+            .map_err(|_| format!("Invalid offset {:?}", value));
+
         match attr {
             // Default offset looks at the start of the file
-            None => Offset {
+            None => Ok(Offset {
                 from: 0,
-                count: 0,
-            },
+                to: 0,
+                from_end: false,
+            }),
             Some(offset) => {
-                match offset.split_once(":") {
-                    // Some offset have a start and a count to check
-                    Some((start, count)) => Offset {
-                        from: u32::from_str(start).unwrap(),
-                        count: u32::from_str(count).unwrap(),
-                    },
-                    // Others just have a start value
-                    None => Offset {
-                        from: u32::from_str(offset.as_str()).unwrap(),
-                        count: 0,
+                match offset.strip_prefix(NEGATIVE_SIGN) {
+                    Some(distance) => {
+                        let (from, to): (u32, u32) = match distance.split_once(":") {
+                            // Ranges must count back from the end at both ends
+                            Some((start, end)) => match end.strip_prefix(NEGATIVE_SIGN) {
+                                Some(end) => (parse(start)?, parse(end)?),
+                                None => return Err(format!("Mixed offset range {:?}", offset))
+                            },
+                            None => (parse(distance)?, parse(distance)?)
+                        };
+
+                        // Matches start furthest from the end and move towards it
+                        if to > from {
+                            return Err(format!("Reversed offset range {:?}", offset));
+                        }
+
+                        Ok(Offset { from, to, from_end: true })
+                    }
+                    None => match offset.split_once(":") {
+                        // Some offset have a start and an end to check up to
+                        Some((start, to)) => Ok(Offset {
+                            from: parse(start)?,
+                            to: parse(to)?,
+                            from_end: false,
+                        }),
+                        // Others just have a start value
+                        None => Ok(Offset {
+                            from: parse(offset.as_str())?,
+                            to: 0,
+                            from_end: false,
+                        })
                     }
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
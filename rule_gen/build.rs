@@ -1,28 +1,117 @@
 #![feature(let_chains)]
 extern crate core;
+extern crate regex;
+extern crate regex_syntax;
 extern crate rule_def;
+extern crate serde;
 extern crate xml;
 
+mod static_source;
+
 use std::{env, u8};
+use std::borrow::Cow;
+use std::cmp::max;
+use std::convert::TryFrom;
 use std::collections::{HashMap, VecDeque};
-use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use regex::bytes::Regex;
+use regex_syntax::ast;
 use xml::attribute::OwnedAttribute;
+use xml::common::{Position, TextPosition};
 use xml::EventReader;
 use xml::name::OwnedName;
 use xml::reader::XmlEvent;
 
-use rule_def::{GlobRule, GlobType, MagicRule, Match, MediaTypeRegistry, Multi, Offset, Single};
+use rule_def::{anchored_regex, full_match_regex, GlobRule, GlobType, List, MagicRule, Match, MatchValueType, MediaTypeMetadata, MediaTypeRegistry, Multi, Offset, ReadPlan, RootXmlRule, Single, Text, trim_parameters};
+
+use self::static_source::to_static_source;
 
 const TIKA_MIMETYPES_PATH: &str = "./tika-mimetypes.xml";
 
 const RULE_REGISTRY_FILE: &str = "rule_registry.rs";
+const READ_AHEAD_FILE: &str = "read_ahead.rs";
 
 type XmlElement = (OwnedName, Vec<OwnedAttribute>);
 
+// A problem with an element, optionally caused by one of its attributes
+struct ElementError {
+    attribute: Option<&'static str>,
+    message: String,
+}
+
+impl ElementError {
+    fn element(message: String) -> ElementError {
+        ElementError { attribute: None, message }
+    }
+
+    fn attribute(attribute: &'static str, message: String) -> ElementError {
+        ElementError { attribute: Some(attribute), message }
+    }
+}
+
+// A problem found while parsing the rules with enough context to find it in the XML
+pub(crate) struct RuleError {
+    file: String,
+    position: TextPosition,
+    media_type: Option<String>,
+    attribute: Option<&'static str>,
+    message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Positions are zero based but editors count from one
+        write!(f, "{}:{}:{}:", self.file, self.position.row + 1, self.position.column + 1)?;
+
+        if let Some(media_type) = &self.media_type {
+            write!(f, " {}:", media_type)?;
+        }
+
+        if let Some(attribute) = self.attribute {
+            write!(f, " {}:", attribute)?;
+        }
+
+        write!(f, " {}", self.message)
+    }
+}
+
+// Collects every problem in a file rather than stopping at the first
+struct ErrorCollector {
+    file: String,
+    errors: Vec<RuleError>,
+}
+
+impl ErrorCollector {
+    fn record<T>(&mut self, position: TextPosition, media_type: &Option<String>, result: Result<T, ElementError>) -> Option<T> {
+        self.record_all(position, media_type, result.map_err(|error| vec![error]))
+    }
+
+    // An element may have a problem with more than one of its attributes
+    fn record_all<T>(&mut self, position: TextPosition, media_type: &Option<String>, result: Result<T, Vec<ElementError>>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(errors) => {
+                for error in errors {
+                    self.errors.push(RuleError {
+                        file: self.file.clone(),
+                        position,
+                        media_type: media_type.clone(),
+                        attribute: error.attribute,
+                        message: error.message,
+                    });
+                }
+
+                None
+            }
+        }
+    }
+}
+
 fn main() {
     let out_dir: String = env::var("OUT_DIR").unwrap();
 
@@ -32,7 +121,28 @@ fn main() {
             .expect("Could not find tika-mimetypes.xml"));
 
     // Initialise parser and extract rules from XML
-    let type_registry: MediaTypeRegistry = parse_xml_rules(EventReader::new(reader));
+    let type_registry: MediaTypeRegistry = match parse_xml_rules(TIKA_MIMETYPES_PATH, EventReader::new(reader)) {
+        Ok(type_registry) => type_registry,
+        Err(errors) => {
+            // Report every problem so they can all be fixed in one go
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+
+            panic!("Found {} problems in {}", errors.len(), TIKA_MIMETYPES_PATH);
+        }
+    };
+
+    // Worked out here so detectors over the compiled rules don't walk every clause
+    let read_plan: ReadPlan = ReadPlan::new(&type_registry.magic_rules);
+
+    let read_plan_source: String = to_static_source(&read_plan)
+        .expect("Failed to write read plan as static source");
+
+    // Enough of the input to evaluate any magic rule
+    fs::write(Path::new(&out_dir).join(READ_AHEAD_FILE),
+              format!("pub const MAX_READ_AHEAD: usize = {};\n\npub static READ_PLAN: ReadPlan = {};\n", read_plan.read_ahead, read_plan_source))
+        .expect("Failed to write read ahead size");
 
     let path: PathBuf = Path::new(&out_dir).join(RULE_REGISTRY_FILE);
 
@@ -45,7 +155,8 @@ fn main() {
 
     let mut writer: BufWriter<File> = BufWriter::new(out_file);
 
-    let output_string: String = uneval::to_string(type_registry)
+    // Written as borrowed data so the registry is a static rather than built on every load
+    let output_string: String = to_static_source(&type_registry)
         .expect("Failed to serialise rule registry as rust source");
 
     writer.write_all(output_string.as_bytes()).unwrap();
@@ -56,37 +167,112 @@ const MIME_TYPE_FIELD: &str = "type";
 
 const GLOB_ELEMENT: &str = "glob";
 const PATTERN_FIELD: &str = "pattern";
-const ASTERISK: &str = "*";
+const ASTERISK: char = '*';
+const WILDCARDS: [char; 3] = [ASTERISK, '?', '['];
 const IS_REGEX_FIELD: &str = "isregex";
+const CASE_SENSITIVE_FIELD: &str = "case-sensitive";
+const CASE_INSENSITIVE_FLAG: &str = "(?i)";
+const TRUE: &str = "true";
+const EXTENSION_DOT: char = '.';
 
 const MAGIC_ELEMENT: &str = "magic";
 const PRIORITY_FIELD: &str = "priority";
 
 const MATCH_ELEMENT: &str = "match";
 const OFFSET_FIELD: &str = "offset";
+const MATCH_TYPE_FIELD: &str = "type";
+const MASK_FIELD: &str = "mask";
 const VALUE_FIELD: &str = "value";
 const MIN_SHOULD_MATCH_FIELD: &str = "minShouldMatch";
 
 const SUB_CLASS_ELEMENT: &str = "sub-class-of";
 
+const ALIAS_ELEMENT: &str = "alias";
+
+const COMMENT_ELEMENT: &str = "_comment";
+const ACRONYM_ELEMENT: &str = "acronym";
+const LINK_ELEMENT: &str = "link";
+const UTI_ELEMENT: &str = "uti";
+
+const ROOT_XML_ELEMENT: &str = "root-XML";
+const LOCAL_NAME_FIELD: &str = "localName";
+const NAMESPACE_URI_FIELD: &str = "namespaceURI";
+
 const HEX_PREFIX: &str = "0x";
+const OCTAL_PREFIX: &str = "0";
 
-const BACKSLASH_INT: u8 = 92;
 const BACKSLASH_CHAR: char = '\\';
 
-fn parse_match(value: &String) -> Vec<u8> {
+// Types whose regex magic is valid for Tika but relies on syntax the regex crate lacks
+// Their regex clauses can never match, any other unsupported pattern fails the build
+const UNSUPPORTED_REGEX_TYPES: [&str; 2] = ["application/x-dbf", "application/x-ms-owner"];
+
+// Parse a numeric value using C conventions to determine the radix
+fn parse_integer(value: &String) -> Result<u32, String> {
+    let (digits, radix): (&str, u32) = if let Some(hex_str) = value.strip_prefix(HEX_PREFIX) {
+        (hex_str, 16)
+    } else if value.len() > 1 && value.starts_with(OCTAL_PREFIX) {
+        (&value[1..], 8)
+    } else {
+        (value.as_str(), 10)
+    };
+
+    u32::from_str_radix(digits, radix).map_err(|_| format!("Invalid numeric value {:?}", value))
+}
+
+fn parse_u8(value: &String) -> Result<u8, String> {
+    parse_integer(value).and_then(|number: u32|
+        u8::try_from(number).map_err(|_| format!("Value {:?} does not fit in 8 bits", value)))
+}
+
+fn parse_u16(value: &String) -> Result<u16, String> {
+    parse_integer(value).and_then(|number: u32|
+        u16::try_from(number).map_err(|_| format!("Value {:?} does not fit in 16 bits", value)))
+}
+
+// Collect up to max digits of the radix from the start index
+fn take_digits(chars: &[char], start: usize, max: usize, radix: u32) -> String {
+    chars.iter().skip(start).take(max).take_while(|char| char.is_digit(radix)).collect()
+}
+
+fn push_char(decoded_bytes: &mut Vec<u8>, char: char) {
+    decoded_bytes.extend_from_slice(char.to_string().as_bytes());
+}
+
+fn parse_match(value: &String, value_type: &MatchValueType) -> Result<Vec<u8>, String> {
+    // Numeric values are laid out in the byte order of their type
+    // Tika treats host byte order as little endian
+    match value_type {
+        MatchValueType::Byte =>
+            return parse_u8(value).map(|number: u8| vec![number]),
+        MatchValueType::Big16 =>
+            return parse_u16(value).map(|number: u16| number.to_be_bytes().to_vec()),
+        MatchValueType::Little16 | MatchValueType::Host16 =>
+            return parse_u16(value).map(|number: u16| number.to_le_bytes().to_vec()),
+        MatchValueType::Big32 =>
+            return parse_integer(value).map(|number: u32| number.to_be_bytes().to_vec()),
+        MatchValueType::Little32 | MatchValueType::Host32 =>
+            return parse_integer(value).map(|number: u32| number.to_le_bytes().to_vec()),
+        MatchValueType::String | MatchValueType::StringIgnoreCase | MatchValueType::Regex |
+        MatchValueType::UnicodeLe | MatchValueType::UnicodeBe => {}
+    }
+
     // Value must be a single hex value
-    if value.starts_with(HEX_PREFIX) {
-        let (_, hex_str) = value.split_once(HEX_PREFIX).unwrap();
+    if let Some(hex_str) = value.strip_prefix(HEX_PREFIX) {
+        // Odd length values have an implied leading zero
+        let hex_str: String = if hex_str.len() % 2 == 0 {
+            hex_str.to_string()
+        } else {
+            format!("0{}", hex_str)
+        };
 
-        let x: Vec<u8> = (0..hex_str.len())
-            // TODO some cleanup and commenting here
+        // Each pair of characters is a single byte
+        return (0..hex_str.len())
             .step_by(2)
-            .map(|idx| u8::from_str_radix(&hex_str[idx..idx + 2], 16).unwrap())
+            .map(|idx| hex_str.get(idx..idx + 2)
+                .and_then(|byte_str| u8::from_str_radix(byte_str, 16).ok())
+                .ok_or_else(|| format!("Invalid hex value {:?}", value)))
             .collect();
-
-        // TODO match every hex byte just by knowing the length
-        return x;
     }
 
     let chars: Vec<char> = value.chars().collect();
@@ -97,109 +283,336 @@ fn parse_match(value: &String) -> Vec<u8> {
     let max: usize = chars.len();
 
     while idx < max {
-        let current: &char = chars.get(idx).unwrap();
-
-        if current == &BACKSLASH_CHAR {
-            match chars.get(idx + 1).unwrap() {
-                // If we have a double backslash
-                &BACKSLASH_CHAR => {
-                    // Decode as a single
-                    decoded_bytes.push(BACKSLASH_INT);
-                    idx += 1;
-                }
-                // Decode following characters as a hex value
-                &'x' => {
-                    let hex_str: String = chars.as_slice()[idx + 2..idx + 4].iter().collect();
-                    let number: u8 = u8::from_str_radix(&hex_str, 16).unwrap();
+        let current: char = chars[idx];
+        idx += 1;
+
+        if current != BACKSLASH_CHAR {
+            push_char(&mut decoded_bytes, current);
+
+            continue;
+        }
 
-                    decoded_bytes.push(number);
+        let escaped: char = *chars.get(idx)
+            .ok_or_else(|| format!("Trailing backslash in {:?}", value))?;
+        idx += 1;
 
-                    idx += 3;
+        match escaped {
+            // Escaped characters which represent themselves
+            BACKSLASH_CHAR | '"' | '\'' | ' ' => push_char(&mut decoded_bytes, escaped),
+            'n' => decoded_bytes.push(b'\n'),
+            'r' => decoded_bytes.push(b'\r'),
+            't' => decoded_bytes.push(b'\t'),
+            // Decode up to two following characters as a hex value
+            'x' => {
+                let hex_str: String = take_digits(&chars, idx, 2, 16);
+
+                if hex_str.is_empty() {
+                    return Err(format!("Missing hex digits after \\x in {:?}", value));
                 }
-                // TODO impl the other mental formats
-                _ => {}
-            };
-        } else {
-            for b in current.to_string().as_bytes() {
-                decoded_bytes.push(*b);
+
+                decoded_bytes.push(u8::from_str_radix(&hex_str, 16).unwrap());
+                idx += hex_str.len();
+            }
+            // Decode exactly four following characters as a unicode code point
+            'u' => {
+                let hex_str: String = take_digits(&chars, idx, 4, 16);
+
+                let code_point: char = u32::from_str_radix(&hex_str, 16).ok()
+                    .filter(|_| hex_str.len() == 4)
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("Invalid unicode escape in {:?}", value))?;
+
+                push_char(&mut decoded_bytes, code_point);
+                idx += hex_str.len();
+            }
+            // Decode up to three characters including this one as an octal value
+            '0'..='7' => {
+                let octal_str: String = take_digits(&chars, idx - 1, 3, 8);
+
+                let number: u8 = u16::from_str_radix(&octal_str, 8).ok()
+                    .and_then(|number: u16| u8::try_from(number).ok())
+                    .ok_or_else(|| format!("Octal escape \\{} out of range in {:?}", octal_str, value))?;
+
+                decoded_bytes.push(number);
+                idx += octal_str.len() - 1;
             }
+            _ => return Err(format!("Unknown escape \\{} in {:?}", escaped, value))
         }
-        idx += 1;
     }
 
-    decoded_bytes
+    // Unicode strings are compared as UTF-16 in the byte order of their type
+    let to_bytes: fn(u16) -> [u8; 2] = match value_type {
+        MatchValueType::UnicodeLe => u16::to_le_bytes,
+        MatchValueType::UnicodeBe => u16::to_be_bytes,
+        _ => return Ok(decoded_bytes)
+    };
+
+    let decoded: String = String::from_utf8(decoded_bytes)
+        .map_err(|_| format!("Invalid unicode value {:?}", value))?;
+
+    Ok(decoded.encode_utf16().flat_map(to_bytes).collect())
+}
+
+// Decode a regex value and check it compiles so a bad pattern fails the build
+fn parse_regex(media_type: &String, value: &String) -> Result<Vec<u8>, String> {
+    let source: String = parse_match(value, &MatchValueType::Regex)?.iter()
+        .map(|byte| match byte {
+            // Escape anything unprintable so it matches the raw byte
+            0x20..=0x7E => (*byte as char).to_string(),
+            _ => format!("\\x{:02X}", byte)
+        })
+        .collect();
+
+    match ast::parse::Parser::new().parse(&source) {
+        Err(error) if matches!(error.kind(), ast::ErrorKind::UnsupportedLookAround | ast::ErrorKind::UnsupportedBackreference) => {
+            if !UNSUPPORTED_REGEX_TYPES.contains(&media_type.as_str()) {
+                return Err(format!("Unsupported regex {:?}: {}, list the type in UNSUPPORTED_REGEX_TYPES to accept it",
+                                   value, error.kind()));
+            }
+        }
+        Err(error) => return Err(format!("Invalid regex {:?}: {}", value, error.kind())),
+        Ok(_) => {
+            if let Err(error) = Regex::new(&anchored_regex(&source)) {
+                return Err(format!("Invalid regex {:?}: {}", value, error));
+            }
+        }
+    }
+
+    Ok(source.into_bytes())
 }
 
 // Create a match condition from an XML attribute
-fn create_match_condition(attributes: &Vec<OwnedAttribute>) -> Match {
+// Each attribute is checked in turn so every problem with the element is reported together
+fn create_match_condition(media_type: &String, attributes: &Vec<OwnedAttribute>) -> Result<Match, Vec<ElementError>> {
     match extract_xml_field(&attributes, MIN_SHOULD_MATCH_FIELD) {
         // Some match clauses have a minShouldMatch condition with nested match statements
         Some(str) => {
-            let min_to_match: u8 = u8::from_str(str.as_str()).unwrap();
+            let min_to_match: u8 = u8::from_str(str.as_str()).map_err(|_|
+                vec![ElementError::attribute(MIN_SHOULD_MATCH_FIELD, format!("Invalid count {:?}", str))])?;
 
-            Match::Multi(Multi { min_to_match, conditions: vec![] })
+            Ok(Match::Multi(Multi { min_to_match, conditions: vec![].into() }))
         }
         // Regular magic condition
         None => {
-            let string: String = extract_xml_field(&attributes, VALUE_FIELD).unwrap();
+            let value_type: Result<MatchValueType, ElementError> = MatchValueType::from_attr(extract_xml_field(&attributes, MATCH_TYPE_FIELD))
+                .map_err(|message| ElementError::attribute(MATCH_TYPE_FIELD, message));
+
+            // The value and mask of an unsupported type are still checked as strings
+            let parse_type: &MatchValueType = value_type.as_ref().unwrap_or(&MatchValueType::String);
+
+            let bytes: Result<Vec<u8>, ElementError> = required_xml_field(&attributes, VALUE_FIELD)
+                .and_then(|string| match parse_type {
+                    MatchValueType::Regex => parse_regex(media_type, &string),
+                    _ => parse_match(&string, parse_type),
+                }.map_err(|message| ElementError::attribute(VALUE_FIELD, message)));
+
+            // Masks are encoded in the same way as the value they apply to
+            let mask: Result<Option<Vec<u8>>, ElementError> = extract_xml_field(&attributes, MASK_FIELD)
+                .map(|mask| match parse_type {
+                    MatchValueType::Regex => Err("A regex can't be masked".to_string()),
+                    _ => parse_match(&mask, parse_type),
+                })
+                .transpose()
+                .map_err(|message| ElementError::attribute(MASK_FIELD, message));
+
+            let offset: Result<Offset, ElementError> = Offset::from_attr(extract_xml_field(&attributes, OFFSET_FIELD))
+                .map_err(|message| ElementError::attribute(OFFSET_FIELD, message));
+
+            match (value_type, bytes, mask, offset) {
+                (Ok(value_type), Ok(mut bytes), Ok(mut mask), Ok(offset)) => {
+                    if let Some(mask) = &mut mask {
+                        pad_masked_value(&mut bytes, mask);
+                    }
 
-            Match::Single(Single {
-                offset: Offset::from_attr(extract_xml_field(&attributes, OFFSET_FIELD)),
-                bytes: parse_match(&string),
-                conditions: vec![],
-            })
+                    Ok(Match::Single(Single {
+                        offset,
+                        value_type,
+                        bytes: bytes.into(),
+                        mask: mask.map(List::from),
+                        conditions: vec![].into(),
+                    }))
+                }
+                (value_type, bytes, mask, offset) =>
+                    Err(vec![value_type.err(), bytes.err(), mask.err(), offset.err()].into_iter().flatten().collect())
+            }
         }
     }
 }
 
+// As in Tika the shorter of a value and its mask is padded to the length of the other
+// Bytes past the end of the value must be zero once masked, those past the mask must match exactly
+fn pad_masked_value(bytes: &mut Vec<u8>, mask: &mut Vec<u8>) {
+    let len: usize = max(bytes.len(), mask.len());
+
+    bytes.resize(len, 0);
+    mask.resize(len, u8::MAX);
+}
+
 const EMPTY: &str = "";
 
+// Translate shell wildcards into an equivalent regex
+fn glob_to_regex(pattern: &str) -> String {
+    let mut regex_str: String = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(char) = chars.next() {
+        match char {
+            ASTERISK => regex_str.push_str(".*"),
+            '?' => regex_str.push('.'),
+            // Character classes carry over apart from shell style negation
+            '[' => match chars.as_str().split_once(']') {
+                Some((class, rest)) => {
+                    regex_str.push('[');
+
+                    let class: &str = match class.strip_prefix('!') {
+                        Some(negated) => {
+                            regex_str.push('^');
+
+                            negated
+                        }
+                        None => class,
+                    };
+
+                    for char in class.chars() {
+                        if matches!(char, '[' | '\\' | '&' | '~') {
+                            regex_str.push('\\');
+                        }
+
+                        regex_str.push(char);
+                    }
+
+                    regex_str.push(']');
+
+                    chars = rest.chars();
+                }
+                // Without a closing bracket it's just a bracket
+                None => regex_str.push_str(&regex::escape(&char.to_string())),
+            },
+            _ => regex_str.push_str(&regex::escape(&char.to_string())),
+        }
+    }
+
+    regex_str
+}
+
 // Create a glob condition from an XML attribute
-fn create_glob_rule(media_type: &Option<String>, attributes: &Vec<OwnedAttribute>) -> GlobRule {
+fn create_glob_rule(media_type: &String, attributes: &Vec<OwnedAttribute>) -> Result<GlobRule, ElementError> {
     // Mandatory field on all glob entries
-    let pattern: String = extract_xml_field(&attributes, PATTERN_FIELD).unwrap();
-
-    let glob_type = match extract_xml_field(&attributes, IS_REGEX_FIELD) {
-        Some(_) => GlobType::Regex,
-        // Default to contains if field unset
-        _ => {
-            // Use position of asterisks to determine GlobType
-            if pattern.starts_with(ASTERISK) {
-                if pattern.ends_with(ASTERISK) {
-                    GlobType::Contains
-                } else {
-                    GlobType::EndsWith
-                }
-            } else {
-                GlobType::StartsWith
-            }
+    let pattern: String = required_xml_field(attributes, PATTERN_FIELD)?;
+
+    let is_regex: bool = extract_xml_field(attributes, IS_REGEX_FIELD).is_some_and(|value| value == TRUE);
+    // Globs are case insensitive unless declared otherwise
+    let case_sensitive: bool = extract_xml_field(attributes, CASE_SENSITIVE_FIELD).is_some_and(|value| value == TRUE);
+
+    let (glob_type, matched): (GlobType, String) = if is_regex {
+        (GlobType::Regex, pattern.clone())
+    } else {
+        // Asterisks at either end are covered by GlobType
+        let (leading, rest): (bool, &str) = match pattern.strip_prefix(ASTERISK) {
+            Some(rest) => (true, rest),
+            None => (false, pattern.as_str()),
+        };
+
+        let (trailing, literal): (bool, &str) = match rest.strip_suffix(ASTERISK) {
+            Some(literal) => (true, literal),
+            None => (false, rest),
+        };
+
+        if literal.contains(WILDCARDS) {
+            // Anything more complex than a literal with wildcard ends becomes a regex
+            (GlobType::Regex, glob_to_regex(&pattern))
+        } else {
+            let glob_type: GlobType = match (leading, trailing) {
+                (false, false) => GlobType::Exact,
+                (true, false) => GlobType::EndsWith,
+                (false, true) => GlobType::StartsWith,
+                (true, true) => GlobType::Contains,
+            };
+
+            (glob_type, literal.to_string())
         }
     };
 
-    GlobRule {
-        // Asterisk behaviour is covered by GlobType
-        media_type: media_type.as_ref().unwrap().clone(),
-        pattern: pattern.replace(ASTERISK, EMPTY),
+    // Lower case names are compared against insensitive globs
+    let matched: String = match glob_type {
+        _ if case_sensitive => matched,
+        GlobType::Regex => format!("{}{}", CASE_INSENSITIVE_FLAG, matched),
+        _ => matched.to_lowercase(),
+    };
+
+    // Check regex globs compile so a bad pattern fails the build
+    if glob_type == GlobType::Regex {
+        if let Err(error) = Regex::new(&full_match_regex(&matched)) {
+            return Err(ElementError::attribute(PATTERN_FIELD, format!("Invalid regex {:?}: {}", matched, error)));
+        }
+    }
+
+    Ok(GlobRule {
+        media_type: media_type.clone().into(),
+        pattern: matched.into(),
+        declared: pattern.into(),
         glob_type,
+        case_sensitive,
+    })
+}
+
+// The extension of a case insensitive glob like *.tar.gz
+fn glob_extension(glob_rule: &GlobRule) -> Option<String> {
+    if glob_rule.glob_type != GlobType::EndsWith || glob_rule.case_sensitive {
+        return None;
     }
+
+    glob_rule.pattern.strip_prefix(EXTENSION_DOT)
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| extension.to_string())
 }
 
 // Create a magic rule from an XML attribute and any nested match blocks
-fn create_magic_rule(media_type: &Option<String>, attributes: &Vec<OwnedAttribute>) -> MagicRule {
+fn create_magic_rule(media_type: &String, attributes: &Vec<OwnedAttribute>) -> Result<MagicRule, ElementError> {
     let priority: u8 = match extract_xml_field(&attributes, PRIORITY_FIELD) {
-        Some(str) => u8::from_str(str.as_str()).unwrap(),
+        Some(str) => u8::from_str(str.as_str()).map_err(|_|
+            ElementError::attribute(PRIORITY_FIELD, format!("Invalid priority {:?}", str)))?,
         // Default priority to zero if not populated
         None => 0
     };
 
-    MagicRule { media_type: media_type.as_ref().unwrap().clone(), priority, conditions: vec![] }
+    Ok(MagicRule { media_type: media_type.clone().into(), priority, conditions: vec![].into() })
 }
 
-fn parse_xml_rules(event_reader: EventReader<BufReader<File>>) -> MediaTypeRegistry {
+// Create a root element rule from an XML attribute
+fn create_root_xml_rule(media_type: &String, attributes: &Vec<OwnedAttribute>) -> Result<RootXmlRule, ElementError> {
+    // Treat empty attributes the same as missing ones
+    let non_empty_field = |key: &str| extract_xml_field(&attributes, key).filter(|value| !value.is_empty());
+
+    let local_name: Option<String> = non_empty_field(LOCAL_NAME_FIELD);
+    let namespace_uri: Option<String> = non_empty_field(NAMESPACE_URI_FIELD);
+
+    if local_name.is_none() && namespace_uri.is_none() {
+        return Err(ElementError::element(format!("Requires a {} or {}", LOCAL_NAME_FIELD, NAMESPACE_URI_FIELD)));
+    }
+
+    Ok(RootXmlRule {
+        media_type: media_type.clone().into(),
+        local_name: local_name.map(Text::from),
+        namespace_uri: namespace_uri.map(Text::from),
+    })
+}
+
+// Rules must be nested within a mime-type element
+fn enclosing_type(media_type: &Option<String>) -> Result<&String, ElementError> {
+    media_type.as_ref().ok_or_else(|| ElementError::element("Must be within a mime-type element".into()))
+}
+
+pub(crate) fn parse_xml_rules(file: &str, mut event_reader: EventReader<BufReader<File>>) -> Result<MediaTypeRegistry, Vec<RuleError>> {
+    let mut errors: ErrorCollector = ErrorCollector { file: file.into(), errors: vec![] };
+
     let mut glob_rules: Vec<GlobRule> = vec![];
     let mut magic_rules: Vec<MagicRule> = vec![];
+    let mut root_xml_rules: Vec<RootXmlRule> = vec![];
 
     let mut sub_types: HashMap<String, Vec<String>> = Default::default();
+    let mut aliases: HashMap<String, String> = Default::default();
+    let mut metadata: HashMap<String, MediaTypeMetadata> = Default::default();
 
     // Parent XML elements
     let mut elements: VecDeque<XmlElement> = Default::default();
@@ -208,24 +621,77 @@ fn parse_xml_rules(event_reader: EventReader<BufReader<File>>) -> MediaTypeRegis
     let mut curr_magic: Option<MagicRule> = None;
     // If this type has a parent
     let mut curr_parent: Option<String> = None;
+    let mut curr_metadata: MediaTypeMetadata = Default::default();
+    // Text content of the innermost element
+    let mut curr_text: String = String::new();
 
     // Parent nested match blocks
     let mut nested_match_blocks: Vec<Match> = vec![];
 
-    for event in event_reader {
+    loop {
+        let event = event_reader.next();
+        // Position of the event which was just read
+        let position: TextPosition = event_reader.position();
+
         match event {
             Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                curr_text.clear();
+
                 match name.local_name.as_str() {
                     // Mime type is a mandatory field
-                    MIME_TYPE_ELEMENT => curr_type = extract_xml_field(&attributes, MIME_TYPE_FIELD),
+                    MIME_TYPE_ELEMENT => curr_type = errors.record(position, &None,
+                        required_type_field(&attributes)),
                     // Glob rules can be added immediately
-                    GLOB_ELEMENT => glob_rules.push(create_glob_rule(&curr_type, &attributes)),
+                    GLOB_ELEMENT => {
+                        let glob_rule = enclosing_type(&curr_type)
+                            .and_then(|media_type| create_glob_rule(media_type, &attributes));
+
+                        if let Some(glob_rule) = errors.record(position, &curr_type, glob_rule) {
+                            glob_rules.push(glob_rule);
+                        }
+                    }
                     // Push match elements onto the stack to support deep nesting
-                    MATCH_ELEMENT => nested_match_blocks.push(create_match_condition(&attributes)),
+                    MATCH_ELEMENT => {
+                        let condition = enclosing_type(&curr_type)
+                            .map_err(|error| vec![error])
+                            .and_then(|media_type| create_match_condition(media_type, &attributes));
+
+                        // Keep a placeholder on error so nesting stays balanced
+                        nested_match_blocks.push(errors.record_all(position, &curr_type, condition)
+                            .unwrap_or(Match::Multi(Multi { min_to_match: 0, conditions: vec![].into() })));
+                    }
                     // Create a magic entry to add nested rules onto
-                    MAGIC_ELEMENT => curr_magic = Some(create_magic_rule(&curr_type, &attributes)),
+                    MAGIC_ELEMENT => {
+                        let magic_rule = enclosing_type(&curr_type)
+                            .and_then(|media_type| create_magic_rule(media_type, &attributes));
+
+                        // Keep a placeholder on error so nested matches are still checked
+                        curr_magic = Some(errors.record(position, &curr_type, magic_rule)
+                            .unwrap_or(MagicRule { media_type: EMPTY.into(), priority: 0, conditions: vec![].into() }));
+                    }
+                    // Root element rules can be added immediately
+                    ROOT_XML_ELEMENT => {
+                        let root_xml_rule = enclosing_type(&curr_type)
+                            .and_then(|media_type| create_root_xml_rule(media_type, &attributes));
+
+                        if let Some(root_xml_rule) = errors.record(position, &curr_type, root_xml_rule) {
+                            root_xml_rules.push(root_xml_rule);
+                        }
+                    }
+                    // Aliases are case insensitive so key them in lower case
+                    ALIAS_ELEMENT => {
+                        let alias = enclosing_type(&curr_type)
+                            .and_then(|media_type| required_type_field(&attributes)
+                                .map(|alias| (alias.to_ascii_lowercase(), media_type.clone())));
+
+                        // As in Tika a repeated alias belongs to the last type to declare it
+                        if let Some((alias, media_type)) = errors.record(position, &curr_type, alias) {
+                            aliases.insert(alias, media_type);
+                        }
+                    }
                     // Add a relationship into the children map
-                    SUB_CLASS_ELEMENT => curr_parent = Some(extract_xml_field(&attributes, MIME_TYPE_FIELD).unwrap()),
+                    SUB_CLASS_ELEMENT => curr_parent = errors.record(position, &curr_type,
+                        required_type_field(&attributes)),
                     _ => {}
                 }
 
@@ -249,7 +715,7 @@ fn parse_xml_rules(event_reader: EventReader<BufReader<File>>) -> MediaTypeRegis
                         } else {
                             // This entry must be directly under a Magic block so add it
                             if let Some(magic) = &mut curr_magic {
-                                magic.conditions.push(current_match);
+                                magic.conditions.to_mut().push(current_match);
                             }
                         }
                     }
@@ -259,10 +725,31 @@ fn parse_xml_rules(event_reader: EventReader<BufReader<File>>) -> MediaTypeRegis
 
                         curr_magic = None;
                     }
+                    // Later comments are notes on the rules rather than a description
+                    COMMENT_ELEMENT => if curr_metadata.description.is_none() {
+                        curr_metadata.description = normalise_text(&curr_text);
+                    },
+                    ACRONYM_ELEMENT => curr_metadata.acronym = normalise_text(&curr_text),
+                    LINK_ELEMENT => curr_metadata.links.to_mut().extend(normalise_text(&curr_text)),
+                    UTI_ELEMENT => curr_metadata.uti = normalise_text(&curr_text),
                     // Once we've collected all the rules for this mime-type
                     MIME_TYPE_ELEMENT => {
-                        // Mime type is a mandatory field
-                        let media_type: String = extract_xml_field(&attributes, MIME_TYPE_FIELD).unwrap();
+                        // A missing type has already been recorded as an error
+                        let media_type: String = required_type_field(&attributes).unwrap_or_default();
+
+                        // Only keep metadata for types which have some
+                        let type_metadata: MediaTypeMetadata = std::mem::take(&mut curr_metadata);
+
+                        if type_metadata != MediaTypeMetadata::default() {
+                            metadata.insert(media_type.clone(), type_metadata);
+                        }
+
+                        // Lookups are lower case so mixed case names such as macroEnabled need an entry too
+                        let lower_case_type: String = media_type.to_ascii_lowercase();
+
+                        if lower_case_type != media_type {
+                            aliases.entry(lower_case_type).or_insert_with(|| media_type.clone());
+                        }
 
                         if let Some(parent) = curr_parent.clone() {
                             // Insert a new entry or add to the existing one
@@ -274,25 +761,84 @@ fn parse_xml_rules(event_reader: EventReader<BufReader<File>>) -> MediaTypeRegis
                         }
 
                         curr_parent = None;
+                        curr_type = None;
                     }
                     _ => {}
                 }
             }
+            Ok(XmlEvent::Characters(text)) | Ok(XmlEvent::CData(text)) => curr_text.push_str(&text),
+            Ok(XmlEvent::EndDocument) => break,
+            // The reader can't recover from malformed XML
+            Err(error) => {
+                errors.record::<()>(error.position(), &curr_type, Err(ElementError::element(error.msg().into())));
+
+                break;
+            }
             _ => {}
         }
     };
 
+    if !errors.errors.is_empty() {
+        return Err(errors.errors);
+    }
+
     magic_rules.sort_by(|a, b| a.priority.partial_cmp(&b.priority).unwrap());
 
-    MediaTypeRegistry {
-        sub_types,
+    // Index extension globs so most names can be looked up without a scan
+    let mut glob_extensions: HashMap<String, Vec<GlobRule>> = Default::default();
 
-        glob_rules,
-        magic_rules,
-    }
+    let glob_rules: Vec<GlobRule> = glob_rules.into_iter()
+        .filter_map(|glob_rule| match glob_extension(&glob_rule) {
+            Some(extension) => {
+                glob_extensions.entry(extension).or_default().push(glob_rule);
+
+                None
+            }
+            None => Some(glob_rule),
+        })
+        .collect();
+
+    // Parents can be declared by an alias so key them by their canonical type
+    let sub_types: HashMap<String, Vec<String>> = sub_types.into_iter()
+        .fold(HashMap::new(), |mut canonical_types, (parent, children)| {
+            let parent: String = aliases.get(&parent.to_ascii_lowercase()).cloned().unwrap_or(parent);
+
+            canonical_types.entry(parent).or_default().extend(children);
+
+            canonical_types
+        });
+
+    // Lookups are kept as sorted lists which can be compiled as static data
+    Ok(MediaTypeRegistry {
+        sub_types: sub_types.into_iter()
+            .map(|(parent, children)| (parent, children.into_iter().map(Text::from).collect()))
+            .collect(),
+        aliases: aliases.into_iter().map(|(alias, media_type)| (alias, media_type.into())).collect(),
+        metadata: metadata.into_iter().collect(),
+
+        glob_extensions: glob_extensions.into_iter().map(|(extension, glob_rules)| (extension, glob_rules.into())).collect(),
+        glob_rules: glob_rules.into(),
+        magic_rules: magic_rules.into(),
+        root_xml_rules: root_xml_rules.into(),
+    })
+}
+
+// Collapse the whitespace of element text which may be spread over several lines
+fn normalise_text(text: &str) -> Option<Text> {
+    Some(text.split_whitespace().collect::<Vec<&str>>().join(" ")).filter(|text| !text.is_empty()).map(Cow::Owned)
 }
 
 // Retrieve the value of an XML field from an attribute
 fn extract_xml_field(attributes: &Vec<OwnedAttribute>, key: &str) -> Option<String> {
     attributes.iter().find(|attr| attr.name.local_name == key).map(|attr| attr.value.clone())
+}
+
+// Retrieve the value of an XML field which must be present
+fn required_xml_field(attributes: &Vec<OwnedAttribute>, key: &'static str) -> Result<String, ElementError> {
+    extract_xml_field(attributes, key).ok_or_else(|| ElementError::attribute(key, "Missing mandatory attribute".into()))
+}
+
+// Retrieve a media type with its parameters trimmed so lookups by the trimmed name find it
+fn required_type_field(attributes: &Vec<OwnedAttribute>) -> Result<String, ElementError> {
+    required_xml_field(attributes, MIME_TYPE_FIELD).map(|media_type| trim_parameters(&media_type))
 }
\ No newline at end of file
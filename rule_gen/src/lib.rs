@@ -1,8 +1,17 @@
 extern crate rule_def;
 
+use std::borrow::Cow;
+use std::ops::Range;
+
 use rule_def::*;
 
-// Include generated code for media type rules
+// Bytes needed from the start of an input to evaluate every magic rule, and which of them are compared
+include!(concat!(env!("OUT_DIR"), "/read_ahead.rs"));
+
+// Generated media type rules, borrowed from the binary so they're never built at runtime
+pub static TYPE_REGISTRY: MediaTypeRegistry = include!(concat!(env!("OUT_DIR"), "/rule_registry.rs"));
+
+// A copy of the registry which only borrows the static rules until it's changed
 pub fn load_type_registry() -> MediaTypeRegistry {
-    return include!(concat!(env!("OUT_DIR"), "/rule_registry.rs"));
-}
\ No newline at end of file
+    TYPE_REGISTRY.clone()
+}
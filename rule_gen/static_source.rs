@@ -0,0 +1,246 @@
+use std::error::Error;
+use std::fmt::{self, Display, Write};
+
+use serde::ser::{self, Impossible, Serialize, Serializer};
+
+// Why a value couldn't be written out as a static initialiser
+#[derive(Debug)]
+pub struct SourceError(String);
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for SourceError {}
+
+impl ser::Error for SourceError {
+    fn custom<T: Display>(message: T) -> SourceError {
+        SourceError(message.to_string())
+    }
+}
+
+// Write a value out as a rust expression which can initialise a static
+// Every string and sequence is assumed to be a Cow so it can borrow from the binary
+pub fn to_static_source<T: Serialize>(value: &T) -> Result<String, SourceError> {
+    let mut writer: SourceWriter = SourceWriter { source: String::new() };
+
+    value.serialize(&mut writer)?;
+
+    Ok(writer.source)
+}
+
+struct SourceWriter {
+    source: String,
+}
+
+impl SourceWriter {
+    fn write<T: Display>(&mut self, value: T) -> Result<(), SourceError> {
+        write!(self.source, "{}", value).map_err(|error| SourceError(error.to_string()))
+    }
+
+    fn unsupported(kind: &str) -> SourceError {
+        SourceError(format!("Can't write a {} as static source", kind))
+    }
+}
+
+impl Serializer for &mut SourceWriter {
+    type Ok = ();
+    type Error = SourceError;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Impossible<(), SourceError>;
+    type SerializeTupleVariant = Impossible<(), SourceError>;
+    type SerializeMap = Impossible<(), SourceError>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), SourceError>;
+
+    fn serialize_bool(self, value: bool) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<(), SourceError> {
+        self.write(value)
+    }
+
+    fn serialize_f32(self, _value: f32) -> Result<(), SourceError> {
+        Err(SourceWriter::unsupported("float"))
+    }
+
+    fn serialize_f64(self, _value: f64) -> Result<(), SourceError> {
+        Err(SourceWriter::unsupported("float"))
+    }
+
+    fn serialize_char(self, value: char) -> Result<(), SourceError> {
+        self.write(format_args!("{:?}", value))
+    }
+
+    // Debug formatting escapes a string the same way a rust literal does
+    fn serialize_str(self, value: &str) -> Result<(), SourceError> {
+        self.write(format_args!("Cow::Borrowed({:?})", value))
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<(), SourceError> {
+        self.write(format_args!("Cow::Borrowed(&{:?})", value))
+    }
+
+    fn serialize_none(self) -> Result<(), SourceError> {
+        self.write("None")
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), SourceError> {
+        self.write("Some(")?;
+        value.serialize(&mut *self)?;
+        self.write(")")
+    }
+
+    fn serialize_unit(self) -> Result<(), SourceError> {
+        self.write("()")
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), SourceError> {
+        self.write(name)
+    }
+
+    fn serialize_unit_variant(self, name: &'static str, _idx: u32, variant: &'static str) -> Result<(), SourceError> {
+        self.write(format_args!("{}::{}", name, variant))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, name: &'static str, value: &T) -> Result<(), SourceError> {
+        self.write(format_args!("{}(", name))?;
+        value.serialize(&mut *self)?;
+        self.write(")")
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), SourceError> {
+        self.write(format_args!("{}::{}(", name, variant))?;
+        value.serialize(&mut *self)?;
+        self.write(")")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, SourceError> {
+        self.write("Cow::Borrowed(&[")?;
+
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, SourceError> {
+        self.write("(")?;
+
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct, SourceError> {
+        Err(SourceWriter::unsupported("tuple struct"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, SourceError> {
+        Err(SourceWriter::unsupported("tuple variant"))
+    }
+
+    // Maps have no static form so are stored as sorted lists instead
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SourceError> {
+        Err(SourceWriter::unsupported("map"))
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self, SourceError> {
+        self.write(format_args!("{} {{", name))?;
+
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, SourceError> {
+        Err(SourceWriter::unsupported("struct variant"))
+    }
+}
+
+impl ser::SerializeSeq for &mut SourceWriter {
+    type Ok = ();
+    type Error = SourceError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SourceError> {
+        value.serialize(&mut **self)?;
+        self.write(",")
+    }
+
+    fn end(self) -> Result<(), SourceError> {
+        self.write("])")
+    }
+}
+
+impl ser::SerializeTuple for &mut SourceWriter {
+    type Ok = ();
+    type Error = SourceError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SourceError> {
+        value.serialize(&mut **self)?;
+        self.write(",")
+    }
+
+    fn end(self) -> Result<(), SourceError> {
+        self.write(")")
+    }
+}
+
+impl ser::SerializeStruct for &mut SourceWriter {
+    type Ok = ();
+    type Error = SourceError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), SourceError> {
+        self.write(format_args!("{}:", key))?;
+        value.serialize(&mut **self)?;
+        self.write(",")
+    }
+
+    fn end(self) -> Result<(), SourceError> {
+        self.write("}")
+    }
+}
@@ -0,0 +1,81 @@
+#![feature(let_chains)]
+extern crate regex;
+extern crate regex_syntax;
+extern crate rule_def;
+extern crate serde;
+extern crate xml;
+
+use std::fs::File;
+use std::io::BufReader;
+
+use rule_def::{MagicRule, MediaTypeRegistry, Single};
+use xml::EventReader;
+
+// The build script is compiled in as a module so its parser can be run against broken rules
+#[allow(dead_code)]
+#[path = "../build.rs"]
+mod build;
+
+fn parse_errors(path: &str) -> Vec<String> {
+    let reader: BufReader<File> = BufReader::new(File::open(path).unwrap());
+
+    match build::parse_xml_rules(path, EventReader::new(reader)) {
+        Ok(_) => vec![],
+        Err(errors) => errors.iter().map(ToString::to_string).collect(),
+    }
+}
+
+#[test]
+fn reports_every_bad_attribute() {
+    let errors: Vec<String> = parse_errors("./tests/data/bad_match.xml");
+
+    // Each broken attribute of the first match is reported rather than only the first found
+    let bad_match: Vec<&String> = errors.iter().filter(|error| error.contains("application/x-bad-match")).collect();
+
+    assert_eq!(bad_match.len(), 3);
+    assert!(bad_match.iter().any(|error| error.contains(" value: ")));
+    assert!(bad_match.iter().any(|error| error.contains(" mask: ")));
+    assert!(bad_match.iter().any(|error| error.contains(" offset: ")));
+
+    // Problems in later types are still found, with the line they're on
+    assert!(errors.iter().any(|error| error.starts_with("./tests/data/bad_match.xml:10:") &&
+        error.contains("application/x-bad-offset: offset: ")));
+
+    // Misspelt match types are reported rather than compared as strings
+    assert!(errors.iter().any(|error| error.starts_with("./tests/data/bad_match.xml:15:") &&
+        error.contains("application/x-bad-type: type: Unsupported match type \"hots16\"")));
+
+    // Regexes are matched as patterns so have no bytes to mask
+    assert!(errors.iter().any(|error| error.contains("application/x-masked-regex: mask: ")));
+    assert_eq!(errors.len(), 6);
+
+    assert!(parse_errors("./tika-mimetypes.xml").is_empty());
+}
+
+#[test]
+fn pads_masked_values() {
+    let reader: BufReader<File> = BufReader::new(File::open("./tests/data/masks.xml").unwrap());
+
+    let registry: MediaTypeRegistry = match build::parse_xml_rules("./tests/data/masks.xml", EventReader::new(reader)) {
+        Ok(registry) => registry,
+        Err(_) => panic!("Masks should parse"),
+    };
+
+    let clause = |media_type: &str| -> Single {
+        let magic_rule: &MagicRule = registry.magic_rules.iter().find(|rule| rule.media_type == media_type).unwrap();
+
+        magic_rule.clauses()[0].clone()
+    };
+
+    // Bytes beyond the value must be zero where the mask has bits set
+    let long_mask: Single = clause("application/x-long-mask");
+
+    assert_eq!(long_mask.bytes.as_ref(), &[0x12, 0x00, 0x00]);
+    assert_eq!(long_mask.mask.as_deref(), Some(&[0xFF, 0x00, 0xF0][..]));
+
+    // Bytes beyond the mask are compared exactly
+    let short_mask: Single = clause("application/x-short-mask");
+
+    assert_eq!(short_mask.bytes.as_ref(), &[0x12, 0x34, 0x56]);
+    assert_eq!(short_mask.mask.as_deref(), Some(&[0xF0, 0xFF, 0xFF][..]));
+}